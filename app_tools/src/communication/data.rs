@@ -1,23 +1,95 @@
 use serde::{Serialize, Deserialize};
+use std::error::Error;
+use std::fmt;
+use crate::communication::status::StatusCode;
+use crate::security::challenge::Challenge;
 
-// Register
+/// Version of the protocol, must be increased on every incompatible change of `Message`
+pub const PROTOCOL_VERSION: u16 = 13;
+
+// Handshake
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct HelloData {
+    pub version: u16,
+}
+
+impl HelloData {
+    pub fn new() -> HelloData {
+        HelloData { version: PROTOCOL_VERSION }
+    }
+
+    pub fn is_compatible(&self) -> bool {
+        self.version == PROTOCOL_VERSION
+    }
+}
+
+impl Default for HelloData {
+    fn default() -> Self {
+        HelloData::new()
+    }
+}
+
+// Operation selection
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub enum AuthenticateRequest {
+    Authenticate,
+    Register,
+    Reset,
+    Exit,
+    Resume,
+    Verify
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub enum ActionRequest {
+    Switch2FA,
+    Logout,
+    EnrolTotp,
+    GenerateRecoveryCodes,
+    ListYubiKeys,
+    AddYubiKey,
+    RemoveYubiKey
+}
+
+// Every action is sent with the session token given at login
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ActionData {
+    pub token: Vec<u8>,
+    pub request: ActionRequest,
+}
+
+// Register, the password never leaves the client, only its SRP verifier
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct RegisterData {
     pub email: String,
-    pub password: String,
+    pub salt: [u8; 16],
+    pub verifier: Vec<u8>,
     pub public_yubikey: Vec<u8>,
+    pub yubikey_label: String,
+    pub attestation: Option<AttestationData>,
+}
+
+// Login with SRP-6a, see `security::srp`
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SrpStartData {
+    pub email: String,
+    pub a_pub: Vec<u8>,
 }
 
 // Challenge - Response data
+// The challenge isn't used by SRP, it is signed by the YubiKey for the second factor
 #[derive(Serialize, Deserialize, Clone, Debug)]
-pub struct ChallengeWithSaltData {
-    pub challenge: [u8; 16],
+pub struct SrpChallengeData {
     pub salt: [u8; 16],
+    /// Argon2 parameters of the account, as a PHC string (see `security::crypto::Argon2Params`)
+    pub password_params: String,
+    pub b_pub: Vec<u8>,
+    pub challenge: Challenge,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct ChallengeData {
-    pub challenge: [u8; 16],
+    pub challenge: Challenge,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -31,9 +103,17 @@ pub struct EmailData {
     pub email: String,
 }
 
+// New password, sent as a verifier with a new salt
 #[derive(Serialize, Deserialize, Clone, Debug)]
-pub struct PasswordData {
-    pub password: String,
+pub struct VerifierData {
+    pub salt: [u8; 16],
+    pub verifier: Vec<u8>,
+}
+
+// Argon2 parameters to derive a new verifier with, as a PHC string
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PasswordParamsData {
+    pub password_params: String,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -41,22 +121,264 @@ pub struct UUIDData {
     pub uuid: String,
 }
 
+/// What an email token confirms
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum VerificationPurpose {
+    Registration,
+    PasswordReset,
+}
+
+// Email token sent on a new connection, when the one of the registration or reset was lost
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct VerificationData {
+    pub purpose: VerificationPurpose,
+    pub email: String,
+    pub uuid: String,
+}
+
 // Server responses
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct ServerResponse {
-    pub message: String,
-    pub success: bool,
+    pub status: StatusCode,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct ServerResponseTwoFA {
-    pub message: String,
-    pub success: bool,
+    pub status: StatusCode,
     pub two_fa: bool,
+    /// SRP proof of the server, empty if the authentication failed
+    pub proof: Vec<u8>,
+    /// Second factors the user can answer with, empty if none is needed
+    pub factors: Vec<SecondFactorType>,
+}
+
+// Sent by the server after each successful login, the token is signed and
+// authorizes the actions of the session, the ticket resumes it on a new connection
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SessionData {
+    pub token: Vec<u8>,
+    pub ticket: Vec<u8>,
+    /// Recovery codes the user can still use
+    pub recovery_codes: u32,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TicketData {
+    pub ticket: Vec<u8>,
+}
+
+// Second factor
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub enum SecondFactorType {
+    YubiKey,
+    Totp,
+    RecoveryCode,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum SecondFactorResponse {
+    /// Signature of the challenge
+    YubiKey(Vec<u8>),
+    /// Code of the authenticator app
+    Totp(String),
+    /// One of the recovery codes, it can't be used again
+    RecoveryCode(String),
+}
+
+/// New recovery codes, the previous ones can't be used anymore
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RecoveryCodesData {
+    pub codes: Vec<String>,
+}
+
+// TOTP enrolment, the secret is shown to the user as an otpauth URI
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TotpSecretData {
+    pub uri: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TotpCodeData {
+    pub code: String,
+}
+
+// YubiKeys of an account, times are UNIX timestamps in seconds
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct YubiKeyInfo {
+    pub label: String,
+    pub created: u64,
+    pub last_used: Option<u64>,
+    /// Serial number of the key, known when it was attested
+    pub serial: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct YubiKeysData {
+    pub keys: Vec<YubiKeyInfo>,
+}
+
+// A new key is added with the signature of the challenge by a key already registered
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct AddYubiKeyData {
+    pub label: String,
+    pub public_key: Vec<u8>,
+    pub signature: Vec<u8>,
+    pub attestation: Option<AttestationData>,
+}
+
+// PIV attestation of a key generated on a YubiKey, both certificates are DER encoded.
+// The slot certificate is signed by the attestation key of the YubiKey (slot f9),
+// whose certificate is signed by Yubico
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct AttestationData {
+    pub certificate: Vec<u8>,
+    pub intermediate: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct YubiKeyLabelData {
+    pub label: String,
 }
 
 // Two factor activation / de-activation
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct ChangeTwoFA {
     pub two_fa_status: bool,
-}
\ No newline at end of file
+}
+
+/// Every payload sent between client and server is wrapped in a `Message`,
+/// so a side that gets out of step receives an error instead of garbage.
+/// New variants must only be added at the end.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum Message {
+    Hello(HelloData),
+    Authenticate(AuthenticateRequest),
+    Action(ActionData),
+    Register(RegisterData),
+    SrpChallenge(SrpChallengeData),
+    Challenge(ChallengeData),
+    Response(ResponseData),
+    Email(EmailData),
+    Verifier(VerifierData),
+    UUID(UUIDData),
+    ServerResponse(ServerResponse),
+    ServerResponseTwoFA(ServerResponseTwoFA),
+    ChangeTwoFA(ChangeTwoFA),
+    Ticket(TicketData),
+    SrpStart(SrpStartData),
+    Session(SessionData),
+    SecondFactor(SecondFactorResponse),
+    TotpSecret(TotpSecretData),
+    TotpCode(TotpCodeData),
+    RecoveryCodes(RecoveryCodesData),
+    YubiKeys(YubiKeysData),
+    AddYubiKey(AddYubiKeyData),
+    YubiKeyLabel(YubiKeyLabelData),
+    PasswordParams(PasswordParamsData),
+    Verification(VerificationData),
+}
+
+/// The message received is not the one the current step expects
+#[derive(Debug)]
+pub struct UnexpectedMessage {
+    pub expected: &'static str,
+    pub received: &'static str,
+}
+
+impl fmt::Display for UnexpectedMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Unexpected message: expected {} but received {}", self.expected, self.received)
+    }
+}
+
+impl Error for UnexpectedMessage {}
+
+macro_rules! message_payloads {
+    ($($payload:ident => $variant:ident),* $(,)?) => {
+        impl Message {
+            pub fn name(&self) -> &'static str {
+                match self {
+                    $(Message::$variant(_) => stringify!($variant),)*
+                }
+            }
+        }
+
+        $(
+            impl From<$payload> for Message {
+                fn from(payload: $payload) -> Self {
+                    Message::$variant(payload)
+                }
+            }
+
+            impl TryFrom<Message> for $payload {
+                type Error = UnexpectedMessage;
+
+                fn try_from(message: Message) -> Result<Self, Self::Error> {
+                    match message {
+                        Message::$variant(payload) => Ok(payload),
+                        other => Err(UnexpectedMessage {
+                            expected: stringify!($variant),
+                            received: other.name(),
+                        }),
+                    }
+                }
+            }
+        )*
+    };
+}
+
+message_payloads! {
+    HelloData => Hello,
+    AuthenticateRequest => Authenticate,
+    ActionData => Action,
+    RegisterData => Register,
+    SrpChallengeData => SrpChallenge,
+    ChallengeData => Challenge,
+    ResponseData => Response,
+    EmailData => Email,
+    VerifierData => Verifier,
+    UUIDData => UUID,
+    ServerResponse => ServerResponse,
+    ServerResponseTwoFA => ServerResponseTwoFA,
+    ChangeTwoFA => ChangeTwoFA,
+    TicketData => Ticket,
+    SrpStartData => SrpStart,
+    SessionData => Session,
+    SecondFactorResponse => SecondFactor,
+    TotpSecretData => TotpSecret,
+    TotpCodeData => TotpCode,
+    RecoveryCodesData => RecoveryCodes,
+    YubiKeysData => YubiKeys,
+    AddYubiKeyData => AddYubiKey,
+    YubiKeyLabelData => YubiKeyLabel,
+    PasswordParamsData => PasswordParams,
+    VerificationData => Verification,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn message_wrapping() {
+        // Pass
+        let message = Message::from(EmailData { email: String::from("email@example.com") });
+        assert_eq!(EmailData::try_from(message).unwrap().email, "email@example.com");
+
+        // Fail
+        let message = Message::from(UUIDData { uuid: String::from("00000000-0000-0000-0000-000000000000") });
+        let error = EmailData::try_from(message).unwrap_err();
+        assert_eq!(error.expected, "Email");
+        assert_eq!(error.received, "UUID");
+    }
+
+    #[test]
+    fn message_version() {
+        // Pass
+        assert!(HelloData::new().is_compatible());
+
+        // Fail
+        assert!(!HelloData { version: PROTOCOL_VERSION + 1 }.is_compatible());
+        assert!(!HelloData { version: PROTOCOL_VERSION - 1 }.is_compatible());
+    }
+}
@@ -0,0 +1,154 @@
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Read, Write};
+use bincode::Options;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+/// Every frame starts with the payload length as a big endian u32
+pub const HEADER_SIZE: usize = 4;
+
+/// Largest payload accepted when nothing else is configured
+pub const DEFAULT_MAX_FRAME_SIZE: u32 = 64 * 1024;
+
+#[derive(Debug)]
+pub enum FrameError {
+    TooLarge { size: u64, max: u32 },
+    Malformed(String),
+    Io(io::Error),
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameError::TooLarge { size, max } =>
+                write!(f, "Frame of {} bytes exceeds the maximum of {} bytes", size, max),
+            FrameError::Malformed(error) => write!(f, "Malformed frame: {}", error),
+            FrameError::Io(error) => write!(f, "io error: {}", error),
+        }
+    }
+}
+
+impl Error for FrameError {}
+
+impl From<io::Error> for FrameError {
+    fn from(error: io::Error) -> Self {
+        FrameError::Io(error)
+    }
+}
+
+/// Bincode is limited to the frame size, so a length inside the payload
+/// can never make us allocate more than the frame itself
+fn bincode_options(max_size: u32) -> impl Options {
+    bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .with_limit(max_size as u64)
+        .reject_trailing_bytes()
+}
+
+/// Serialize a value into a complete frame (header + payload)
+/// # Errors
+/// * `FrameError::TooLarge` - The payload is bigger than `max_size`
+pub fn encode<T>(o: &T, max_size: u32) -> Result<Vec<u8>, FrameError> where T: Serialize {
+    let size = match bincode_options(max_size).serialized_size(o) {
+        Ok(size) => size,
+        Err(_) => return Err(FrameError::TooLarge { size: u64::MAX, max: max_size }),
+    };
+
+    let mut frame = Vec::with_capacity(HEADER_SIZE + size as usize);
+    frame.extend_from_slice(&(size as u32).to_be_bytes());
+    bincode_options(max_size).serialize_into(&mut frame, o)
+        .map_err(|e| FrameError::Malformed(e.to_string()))?;
+    Ok(frame)
+}
+
+/// Read the payload length announced in a header
+/// # Errors
+/// * `FrameError::TooLarge` - The announced length is bigger than `max_size`
+pub fn decode_header(header: [u8; HEADER_SIZE], max_size: u32) -> Result<usize, FrameError> {
+    let size = u32::from_be_bytes(header);
+    if size > max_size {
+        return Err(FrameError::TooLarge { size: size as u64, max: max_size });
+    }
+    Ok(size as usize)
+}
+
+/// Deserialize a payload, it must be consumed entirely
+/// # Errors
+/// * `FrameError::Malformed` - The payload doesn't match the expected type
+pub fn decode_payload<T>(payload: &[u8], max_size: u32) -> Result<T, FrameError> where T: DeserializeOwned {
+    bincode_options(max_size)
+        .deserialize(payload)
+        .map_err(|e| FrameError::Malformed(e.to_string()))
+}
+
+pub fn write_frame<W, T>(writer: &mut W, o: &T, max_size: u32) -> Result<(), FrameError>
+    where W: Write, T: Serialize {
+    writer.write_all(&encode(o, max_size)?)?;
+    Ok(writer.flush()?)
+}
+
+pub fn read_frame<R, T>(reader: &mut R, max_size: u32) -> Result<T, FrameError>
+    where R: Read, T: DeserializeOwned {
+    let mut header = [0; HEADER_SIZE];
+    reader.read_exact(&mut header)?;
+    let mut payload = vec![0; decode_header(header, max_size)?];
+    reader.read_exact(&mut payload)?;
+    decode_payload(&payload, max_size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn frame_round_trip() {
+        let data = (String::from("email@example.com"), vec![1u8, 2, 3], true);
+        let mut stream = Cursor::new(Vec::new());
+        write_frame(&mut stream, &data, DEFAULT_MAX_FRAME_SIZE).unwrap();
+        write_frame(&mut stream, &42u32, DEFAULT_MAX_FRAME_SIZE).unwrap();
+
+        stream.set_position(0);
+        let read: (String, Vec<u8>, bool) = read_frame(&mut stream, DEFAULT_MAX_FRAME_SIZE).unwrap();
+        assert_eq!(read, data);
+        assert_eq!(read_frame::<_, u32>(&mut stream, DEFAULT_MAX_FRAME_SIZE).unwrap(), 42);
+    }
+
+    #[test]
+    fn frame_too_large() {
+        // Announced length bigger than the limit, nothing is allocated
+        let mut stream = Cursor::new(u32::MAX.to_be_bytes().to_vec());
+        assert!(matches!(read_frame::<_, Vec<u8>>(&mut stream, 1024),
+                         Err(FrameError::TooLarge { .. })));
+
+        // Payload bigger than the limit is refused on send
+        assert!(matches!(encode(&vec![0u8; 2048], 1024), Err(FrameError::TooLarge { .. })));
+
+        // Corner cases
+        assert_eq!(decode_header(1024u32.to_be_bytes(), 1024).unwrap(), 1024);
+        assert!(decode_header(1025u32.to_be_bytes(), 1024).is_err());
+    }
+
+    #[test]
+    fn frame_malformed() {
+        // Vec length inside the payload bigger than the payload itself
+        let mut frame = 8u32.to_be_bytes().to_vec();
+        frame.extend_from_slice(&u64::MAX.to_le_bytes());
+        assert!(matches!(read_frame::<_, Vec<u8>>(&mut Cursor::new(frame), 1024),
+                         Err(FrameError::Malformed(_))));
+
+        // Trailing bytes
+        let mut frame = encode(&1u8, 1024).unwrap();
+        frame[3] += 1;
+        frame.push(0);
+        assert!(matches!(read_frame::<_, u8>(&mut Cursor::new(frame), 1024),
+                         Err(FrameError::Malformed(_))));
+
+        // Truncated payload
+        let mut frame = encode(&String::from("truncated"), 1024).unwrap();
+        frame.pop();
+        assert!(matches!(read_frame::<_, String>(&mut Cursor::new(frame), 1024),
+                         Err(FrameError::Io(_))));
+    }
+}
@@ -5,15 +5,34 @@ pub static INVALID_PASSWORD: &str = "Invalid password: must contain at least 1 u
 pub static INVALID_PUBLIC_KEY: &str = "Invalid public key";
 pub static INVALID_UUID: &str = "Invalid uuid: must have format 00000000-0000-0000-0000-000000000000";
 pub static INVALID_PIN: &str = "Invalid pin: must contains at least 6 and maximum 8 characters";
-pub static BAD_UUID: &str = "Bad uuid";
+pub static INVALID_TOTP_CODE: &str = "Invalid code: must contains 6 digits";
+pub static INVALID_RECOVERY_CODE: &str = "Invalid recovery code: must contains 16 letters or digits from 2 to 7";
+pub static BAD_UUID: &str = "Wrong, expired or already used uuid";
 pub static WRONG_KEY: &str = "Wrong yubikey";
+pub static SECOND_FACTOR_REQUIRED: &str = "Please log in again with your second factor to do this";
+pub static WRONG_CODE: &str = "Wrong authenticator code";
+pub static WRONG_RECOVERY_CODE: &str = "Wrong or already used recovery code";
+pub static INVALID_LABEL: &str = "Invalid label: must contains 1 to 32 letters, digits, spaces, '-', '_' or '.' and be unique";
+pub static LAST_YUBIKEY: &str = "The last yubikey can't be removed";
+pub static UNKNOWN_YUBIKEY: &str = "No yubikey with this label";
+pub static INVALID_ATTESTATION: &str = "The key could not be attested as generated on a YubiKey";
 pub static AUTH_FAIL: &str = "Invalid user and password combination";
 pub static ACCOUNT_EXISTING: &str = "An account with same email already exists";
+pub static RATE_LIMITED: &str = "Too many requests from your network, please try again later";
+pub static LOCKED: &str = "Too many failed attempts, please try again later";
+pub static SESSION_EXPIRED: &str = "Session expired or revoked, please authenticate again";
+pub static RESUME_FAIL: &str = "Session expired, please authenticate again";
+pub static INCOMPATIBLE_VERSION: &str = "Incompatible protocol version";
 
 // Success
 pub static EMAIL_SENT: &str = "Email sent";
-pub static VALID_EMAIL: &str = "Email is valid and account exists";
+pub static VALID_EMAIL: &str = "Email is valid, please confirm with your second factor";
 pub static ACCOUNT_REGISTERED: &str = "Account registered";
 pub static AUTH_SUCCESS: &str = "Authentification success";
 pub static AUTH_TWO_FA: &str = "First part of authentification success";
-pub static CORRECT_UUID: &str = "Correct UUID from email";
\ No newline at end of file
+pub static CORRECT_UUID: &str = "Correct UUID from email";
+pub static ACCEPTED: &str = "Request accepted";
+pub static TOTP_ENROLLED: &str = "Authenticator app enrolled";
+pub static YUBIKEY_ADDED: &str = "Yubikey added";
+pub static YUBIKEY_REMOVED: &str = "Yubikey removed";
+pub static PASSWORD_CHANGED: &str = "Password changed";
@@ -0,0 +1,185 @@
+use serde::{Serialize, Deserialize};
+use std::error::Error;
+use std::fmt;
+use crate::communication::messages::*;
+
+/// Outcome of a step sent by the server, programs branch on the code
+/// and the text is only rendered for the user with `message`.
+/// The numeric values are part of the protocol and must never change:
+/// 1xx are successes, 2xx are failures.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(into = "u16", try_from = "u16")]
+pub enum StatusCode {
+    // Success
+    EmailSent = 100,
+    ValidEmail = 101,
+    AccountRegistered = 102,
+    AuthSuccess = 103,
+    AuthTwoFA = 104,
+    CorrectUuid = 105,
+    Accepted = 106,
+    TotpEnrolled = 107,
+    YubiKeyAdded = 108,
+    YubiKeyRemoved = 109,
+    PasswordChanged = 110,
+
+    // Errors
+    InvalidEmail = 200,
+    InvalidPassword = 201,
+    InvalidPublicKey = 202,
+    BadUuid = 203,
+    WrongKey = 204,
+    AuthFail = 205,
+    AccountExisting = 206,
+    ResumeFail = 207,
+    Locked = 208,
+    RateLimited = 209,
+    SessionExpired = 210,
+    SecondFactorRequired = 211,
+    WrongCode = 212,
+    WrongRecoveryCode = 213,
+    InvalidLabel = 214,
+    LastYubiKey = 215,
+    UnknownYubiKey = 216,
+    InvalidAttestation = 217,
+}
+
+const ALL_STATUS: [StatusCode; 29] = [
+    StatusCode::EmailSent,
+    StatusCode::ValidEmail,
+    StatusCode::AccountRegistered,
+    StatusCode::AuthSuccess,
+    StatusCode::AuthTwoFA,
+    StatusCode::CorrectUuid,
+    StatusCode::Accepted,
+    StatusCode::TotpEnrolled,
+    StatusCode::YubiKeyAdded,
+    StatusCode::YubiKeyRemoved,
+    StatusCode::PasswordChanged,
+    StatusCode::InvalidEmail,
+    StatusCode::InvalidPassword,
+    StatusCode::InvalidPublicKey,
+    StatusCode::BadUuid,
+    StatusCode::WrongKey,
+    StatusCode::AuthFail,
+    StatusCode::AccountExisting,
+    StatusCode::ResumeFail,
+    StatusCode::Locked,
+    StatusCode::RateLimited,
+    StatusCode::SessionExpired,
+    StatusCode::SecondFactorRequired,
+    StatusCode::WrongCode,
+    StatusCode::WrongRecoveryCode,
+    StatusCode::InvalidLabel,
+    StatusCode::LastYubiKey,
+    StatusCode::UnknownYubiKey,
+    StatusCode::InvalidAttestation,
+];
+
+impl StatusCode {
+    pub fn is_success(&self) -> bool {
+        (100..200).contains(&u16::from(*self))
+    }
+
+    pub fn message(&self) -> &'static str {
+        match self {
+            StatusCode::EmailSent => EMAIL_SENT,
+            StatusCode::ValidEmail => VALID_EMAIL,
+            StatusCode::AccountRegistered => ACCOUNT_REGISTERED,
+            StatusCode::AuthSuccess => AUTH_SUCCESS,
+            StatusCode::AuthTwoFA => AUTH_TWO_FA,
+            StatusCode::CorrectUuid => CORRECT_UUID,
+            StatusCode::Accepted => ACCEPTED,
+            StatusCode::TotpEnrolled => TOTP_ENROLLED,
+            StatusCode::YubiKeyAdded => YUBIKEY_ADDED,
+            StatusCode::YubiKeyRemoved => YUBIKEY_REMOVED,
+            StatusCode::PasswordChanged => PASSWORD_CHANGED,
+            StatusCode::InvalidEmail => INVALID_EMAIL,
+            StatusCode::InvalidPassword => INVALID_PASSWORD,
+            StatusCode::InvalidPublicKey => INVALID_PUBLIC_KEY,
+            StatusCode::BadUuid => BAD_UUID,
+            StatusCode::WrongKey => WRONG_KEY,
+            StatusCode::AuthFail => AUTH_FAIL,
+            StatusCode::AccountExisting => ACCOUNT_EXISTING,
+            StatusCode::ResumeFail => RESUME_FAIL,
+            StatusCode::Locked => LOCKED,
+            StatusCode::RateLimited => RATE_LIMITED,
+            StatusCode::SessionExpired => SESSION_EXPIRED,
+            StatusCode::SecondFactorRequired => SECOND_FACTOR_REQUIRED,
+            StatusCode::WrongCode => WRONG_CODE,
+            StatusCode::WrongRecoveryCode => WRONG_RECOVERY_CODE,
+            StatusCode::InvalidLabel => INVALID_LABEL,
+            StatusCode::LastYubiKey => LAST_YUBIKEY,
+            StatusCode::UnknownYubiKey => UNKNOWN_YUBIKEY,
+            StatusCode::InvalidAttestation => INVALID_ATTESTATION,
+        }
+    }
+}
+
+impl From<StatusCode> for u16 {
+    fn from(status: StatusCode) -> Self {
+        status as u16
+    }
+}
+
+impl TryFrom<u16> for StatusCode {
+    type Error = String;
+
+    fn try_from(code: u16) -> Result<Self, Self::Error> {
+        ALL_STATUS.iter()
+            .find(|status| u16::from(**status) == code)
+            .copied()
+            .ok_or(format!("Unknown status code {}", code))
+    }
+}
+
+impl fmt::Display for StatusCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message())
+    }
+}
+
+impl Error for StatusCode {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn status_code_mapping() {
+        // The values are part of the protocol
+        assert_eq!(u16::from(StatusCode::EmailSent), 100);
+        assert_eq!(u16::from(StatusCode::AuthTwoFA), 104);
+        assert_eq!(u16::from(StatusCode::InvalidEmail), 200);
+        assert_eq!(u16::from(StatusCode::AccountExisting), 206);
+
+        // Pass
+        for status in ALL_STATUS {
+            assert_eq!(StatusCode::try_from(u16::from(status)).unwrap(), status);
+        }
+
+        // Fail
+        assert!(StatusCode::try_from(0).is_err());
+        assert!(StatusCode::try_from(199).is_err());
+        assert!(StatusCode::try_from(u16::MAX).is_err());
+    }
+
+    #[test]
+    fn status_code_success() {
+        // Pass
+        assert!(StatusCode::AuthSuccess.is_success());
+        assert!(StatusCode::CorrectUuid.is_success());
+
+        // Fail
+        assert!(!StatusCode::AuthFail.is_success());
+        assert!(!StatusCode::WrongKey.is_success());
+    }
+
+    #[test]
+    fn status_code_serialization() {
+        let bytes = bincode::serialize(&StatusCode::BadUuid).unwrap();
+        assert_eq!(bytes, 203u16.to_le_bytes());
+        assert_eq!(bincode::deserialize::<StatusCode>(&bytes).unwrap(), StatusCode::BadUuid);
+        assert!(bincode::deserialize::<StatusCode>(&999u16.to_le_bytes()).is_err());
+    }
+}
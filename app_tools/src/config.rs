@@ -0,0 +1,80 @@
+use std::ops::Deref;
+use std::str::FromStr;
+use std::sync::OnceLock;
+
+/// Settings set once when the program starts and read everywhere through a static.
+/// They are loaded with `load_default` if they are read before being set.
+pub struct ConfigRef<T: 'static> {
+    instance: OnceLock<T>,
+    load_default: fn() -> T,
+}
+
+impl<T> ConfigRef<T> {
+    pub const fn new(load_default: fn() -> T) -> ConfigRef<T> {
+        ConfigRef {
+            instance: OnceLock::new(),
+            load_default,
+        }
+    }
+
+    /// # Errors
+    /// * `String` - The settings have already been set or read
+    pub fn set(&self, config: T) -> Result<(), String> {
+        self.instance.set(config).map_err(|_| "Config already loaded".into())
+    }
+}
+
+impl<T> Deref for ConfigRef<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.instance.get_or_init(self.load_default)
+    }
+}
+
+/// `1`, `true`, `yes` and `on` in any case, anything else is false
+pub fn parse_bool(value: &str) -> bool {
+    matches!(value.to_lowercase().as_str(), "1" | "true" | "yes" | "on")
+}
+
+/// The default is kept, with a warning, when the value isn't a valid number
+pub fn parse_number<T: FromStr>(key: &str, value: &str, default: T) -> T {
+    match value.parse() {
+        Ok(number) => number,
+        Err(_) => {
+            println!("Invalid value for {}, the default one is used", key);
+            default
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn config_ref() {
+        static CONFIG: ConfigRef<u32> = ConfigRef::new(|| 7);
+
+        assert_eq!(*CONFIG, 7);
+        assert!(CONFIG.set(8).is_err());
+        assert_eq!(*CONFIG, 7);
+    }
+
+    #[test]
+    fn parse_values() {
+        // Pass
+        assert!(parse_bool("1"));
+        assert!(parse_bool("True"));
+        assert!(parse_bool("yes"));
+        assert!(parse_bool("ON"));
+        assert_eq!(parse_number("KEY", "42", 8u32), 42);
+
+        // Fail
+        assert!(!parse_bool("0"));
+        assert!(!parse_bool("false"));
+        assert!(!parse_bool(""));
+        assert_eq!(parse_number("KEY", "-1", 8u32), 8);
+        assert_eq!(parse_number("KEY", "forty", 8u32), 8);
+    }
+}
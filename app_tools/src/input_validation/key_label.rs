@@ -0,0 +1,44 @@
+use lazy_static::lazy_static;
+use regex::Regex;
+
+// Name given by the user to a YubiKey, e.g. "Backup key"
+static REGEX_KEY_LABEL: &str = r"[[:alnum:]][[:alnum:] ._-]{0,31}";
+
+pub fn validate_key_label(label: &str) -> bool {
+    lazy_static! {
+        static ref RE: Regex = Regex::new(&format!("^{}$", REGEX_KEY_LABEL)).unwrap();
+    }
+    RE.is_match(label)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::validate_key_label;
+
+    #[test]
+    fn validate_key_label_length() {
+        // Pass
+        assert!(validate_key_label("Main key"));
+
+        // Fail
+        assert!(!validate_key_label(""));
+        assert!(!validate_key_label("000000000000000000000000000000000")); // 33
+
+        // Corner cases
+        assert!(validate_key_label("0")); // 1
+        assert!(validate_key_label("00000000000000000000000000000000")); // 32
+    }
+
+    #[test]
+    fn validate_key_label_characters() {
+        // Pass
+        assert!(validate_key_label("Backup key 2"));
+        assert!(validate_key_label("yubikey_5-nfc.old"));
+
+        // Fail
+        assert!(!validate_key_label(" key")); // leading space
+        assert!(!validate_key_label("-key")); // leading dash
+        assert!(!validate_key_label("key$")); // special chars
+        assert!(!validate_key_label("key\n")); // new line
+    }
+}
\ No newline at end of file
@@ -0,0 +1,33 @@
+use lazy_static::lazy_static;
+use regex::Regex;
+
+// 16 base32 characters, usually grouped by 4 with dashes
+static REGEX_RECOVERY_CODE: &str = r"[A-Za-z2-7]{4}(-?[A-Za-z2-7]{4}){3}";
+
+pub fn validate_recovery_code(code: &str) -> bool {
+    lazy_static! {
+        static ref RE: Regex = Regex::new(&format!("^{}$", REGEX_RECOVERY_CODE)).unwrap();
+    }
+    RE.is_match(code.trim())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::validate_recovery_code;
+
+    #[test]
+    fn validate_recovery_code_format() {
+        // Pass
+        assert!(validate_recovery_code("ABCD-EFGH-IJKL-MNOP"));
+        assert!(validate_recovery_code("abcd-efgh-ijkl-mnop")); // lower case
+        assert!(validate_recovery_code("ABCDEFGHIJKLMNOP")); // without dashes
+        assert!(validate_recovery_code("QRST-UVWX-YZ23-4567"));
+
+        // Fail
+        assert!(!validate_recovery_code(""));
+        assert!(!validate_recovery_code("ABCD-EFGH-IJKL")); // too short
+        assert!(!validate_recovery_code("ABCD-EFGH-IJKL-MNOPQ")); // too long
+        assert!(!validate_recovery_code("ABCD-EFGH-IJKL-MNO1")); // not base32
+        assert!(!validate_recovery_code("ABCD_EFGH_IJKL_MNOP")); // separator
+    }
+}
\ No newline at end of file
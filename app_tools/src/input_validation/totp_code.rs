@@ -0,0 +1,30 @@
+use lazy_static::lazy_static;
+use regex::Regex;
+
+// Codes of authenticator apps have 6 digits (RFC 6238)
+static REGEX_TOTP_CODE: &str = r"[[:digit:]]{6}";
+
+pub fn validate_totp_code(code: &str) -> bool {
+    lazy_static! {
+        static ref RE: Regex = Regex::new(&format!("^{}$", REGEX_TOTP_CODE)).unwrap();
+    }
+    RE.is_match(code)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::validate_totp_code;
+
+    #[test]
+    fn validate_totp_code_format() {
+        // Pass
+        assert!(validate_totp_code("012345"));
+
+        // Fail
+        assert!(!validate_totp_code(""));
+        assert!(!validate_totp_code("01234")); // 5
+        assert!(!validate_totp_code("0123456")); // 7
+        assert!(!validate_totp_code("01234a")); // letter
+        assert!(!validate_totp_code("012 345")); // space
+    }
+}
\ No newline at end of file
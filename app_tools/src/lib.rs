@@ -1,13 +1,23 @@
 pub mod communication {
     pub mod data;
+    pub mod framing;
     pub mod messages;
+    pub mod status;
 }
+pub mod config;
 pub mod input_validation {
     pub mod email;
+    pub mod key_label;
     pub mod password;
     pub mod pin;
+    pub mod recovery_code;
+    pub mod totp_code;
     pub mod uuid;
 }
 pub mod security {
+    pub mod challenge;
     pub mod crypto;
+    pub mod recovery;
+    pub mod srp;
+    pub mod totp;
 }
@@ -0,0 +1,141 @@
+use serde::{Serialize, Deserialize};
+use crate::security::crypto::{constant_time_eq, generate_random_16_bytes};
+
+/// Prefix of every signed challenge, so a signature made for this protocol can't be used for anything else
+const DOMAIN: &[u8] = b"SEC Labo 2 YubiKey challenge v1";
+
+/// Step a challenge is signed for, a signature given for one of them is refused by the others
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ChallengePurpose {
+    Login,
+    PasswordReset,
+    AddYubiKey,
+}
+
+/// What the YubiKey signs. It is built by the server, the client checks that it is meant
+/// for this server and this step before signing it.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Challenge {
+    pub purpose: ChallengePurpose,
+    /// Identity of the server, a server relaying the challenge of another one gets a signature it can't use
+    pub server_id: String,
+    /// Connection of a login or a reset, session of an action
+    pub session_id: [u8; 16],
+    /// UNIX timestamp in seconds of the creation
+    pub timestamp: u64,
+    pub nonce: [u8; 16],
+}
+
+impl Challenge {
+    pub fn new(purpose: ChallengePurpose, server_id: &str, session_id: [u8; 16], timestamp: u64) -> Challenge {
+        let mut nonce = [0; 16];
+        generate_random_16_bytes(&mut nonce);
+        Challenge {
+            purpose,
+            server_id: server_id.to_string(),
+            session_id,
+            timestamp,
+            nonce,
+        }
+    }
+
+    /// The signed bytes: the domain, then every field, the server ID prefixed with its length
+    /// so two different challenges never give the same bytes
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(DOMAIN.len() + 45 + self.server_id.len());
+        bytes.extend_from_slice(DOMAIN);
+        bytes.push(match self.purpose {
+            ChallengePurpose::Login => 1,
+            ChallengePurpose::PasswordReset => 2,
+            ChallengePurpose::AddYubiKey => 3,
+        });
+        bytes.extend_from_slice(&(self.server_id.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(self.server_id.as_bytes());
+        bytes.extend_from_slice(&self.session_id);
+        bytes.extend_from_slice(&self.timestamp.to_be_bytes());
+        bytes.extend_from_slice(&self.nonce);
+        bytes
+    }
+
+    /// Checked by the client before signing
+    /// # Errors
+    /// * `String` - The field that doesn't match
+    pub fn check_origin(&self, purpose: ChallengePurpose, server_id: &str) -> Result<(), String> {
+        if self.purpose != purpose {
+            return Err(format!("Challenge for {:?} instead of {:?}", self.purpose, purpose));
+        }
+        if self.server_id != server_id {
+            return Err(format!("Challenge from server {} instead of {}", self.server_id, server_id));
+        }
+        Ok(())
+    }
+
+    /// Checked by the server before the signature, a challenge older than `lifetime` seconds is stale
+    /// # Errors
+    /// * `String` - The field that doesn't match
+    pub fn check(&self,
+                 purpose: ChallengePurpose,
+                 server_id: &str,
+                 session_id: &[u8; 16],
+                 now: u64,
+                 lifetime: u64) -> Result<(), String> {
+        self.check_origin(purpose, server_id)?;
+        if !constant_time_eq(&self.session_id, session_id) {
+            return Err(String::from("Challenge of another session"));
+        }
+        if self.timestamp > now || now - self.timestamp > lifetime {
+            return Err(String::from("Stale challenge"));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SESSION: [u8; 16] = [1; 16];
+    const NOW: u64 = 1_700_000_000;
+
+    #[test]
+    fn challenge_bytes() {
+        let challenge = Challenge::new(ChallengePurpose::Login, "server", SESSION, NOW);
+        let bytes = challenge.to_bytes();
+        assert!(bytes.starts_with(DOMAIN));
+        assert_eq!(bytes, challenge.clone().to_bytes());
+
+        // Every field changes the bytes
+        let mut other = challenge.clone();
+        other.purpose = ChallengePurpose::PasswordReset;
+        assert_ne!(other.to_bytes(), bytes);
+        let mut other = challenge.clone();
+        other.server_id = String::from("server2");
+        assert_ne!(other.to_bytes(), bytes);
+        let mut other = challenge.clone();
+        other.session_id = [2; 16];
+        assert_ne!(other.to_bytes(), bytes);
+        let mut other = challenge.clone();
+        other.timestamp += 1;
+        assert_ne!(other.to_bytes(), bytes);
+        assert_ne!(Challenge::new(ChallengePurpose::Login, "server", SESSION, NOW).to_bytes(), bytes);
+    }
+
+    #[test]
+    fn challenge_check() {
+        let challenge = Challenge::new(ChallengePurpose::Login, "server", SESSION, NOW);
+
+        // Pass
+        assert!(challenge.check_origin(ChallengePurpose::Login, "server").is_ok());
+        assert!(challenge.check(ChallengePurpose::Login, "server", &SESSION, NOW, 60).is_ok());
+        assert!(challenge.check(ChallengePurpose::Login, "server", &SESSION, NOW + 60, 60).is_ok());
+
+        // Fail
+        assert!(challenge.check_origin(ChallengePurpose::AddYubiKey, "server").is_err());
+        assert!(challenge.check_origin(ChallengePurpose::Login, "other").is_err());
+        assert!(challenge.check(ChallengePurpose::PasswordReset, "server", &SESSION, NOW, 60).is_err());
+        assert!(challenge.check(ChallengePurpose::Login, "serve", &SESSION, NOW, 60).is_err());
+        assert!(challenge.check(ChallengePurpose::Login, "server", &[2; 16], NOW, 60).is_err());
+        assert!(challenge.check(ChallengePurpose::Login, "server", &SESSION, NOW + 61, 60).is_err());
+        assert!(challenge.check(ChallengePurpose::Login, "server", &SESSION, NOW - 1, 60).is_err());
+    }
+}
\ No newline at end of file
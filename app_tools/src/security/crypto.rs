@@ -1,7 +1,9 @@
 use rand::RngCore;
-use argon2::{self, Config};
+use argon2::{self, Config, Variant, Version};
 use sha2::{Sha256, Digest};
 use hmac::{Hmac, Mac};
+use serde::{Serialize, Deserialize};
+use std::fmt;
 
 type HmacSha256 = Hmac<Sha256>;
 
@@ -10,9 +12,195 @@ pub fn generate_random_16_bytes(bytes: &mut [u8; 16]) {
     rng.fill_bytes(bytes);
 }
 
-/// We assume that the hash function will always works
-pub fn hash_argon2(data: &str, salt: &[u8]) -> String {
-    argon2::hash_encoded(data.as_bytes(), salt, &Config::default()).unwrap()
+pub fn generate_random_bytes(bytes: &mut [u8]) {
+    let mut rng = rand::thread_rng();
+    rng.fill_bytes(bytes);
+}
+
+/// HMAC-SHA256 with a key of any length
+pub fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    // HMAC accepts keys of any size
+    let mut mac = HmacSha256::new_from_slice(key).unwrap();
+    mac.update(data);
+    mac.finalize().into_bytes()[..].to_vec()
+}
+
+/// Check a HMAC-SHA256 tag in constant time
+pub fn verify_hmac_sha256(key: &[u8], data: &[u8], tag: &[u8]) -> bool {
+    let mut mac = HmacSha256::new_from_slice(key).unwrap();
+    mac.update(data);
+    mac.verify_slice(tag).is_ok()
+}
+
+/// Compare secrets, MACs or their hashes in a time that only depends on their length,
+/// so a wrong guess doesn't tell how many of its first bytes are right
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    let difference = a.iter().zip(b).fold(0, |difference, (a, b)| difference | (a ^ b));
+    std::hint::black_box(difference) == 0
+}
+
+/// A secret, or the hash of a secret. It doesn't implement `PartialEq` so it can only be compared
+/// in constant time with `matches`, and `Debug` doesn't write it.
+#[derive(Serialize, Deserialize, Clone, Default)]
+#[serde(transparent)]
+pub struct SecretBytes(Vec<u8>);
+
+impl SecretBytes {
+    pub fn new(bytes: Vec<u8>) -> SecretBytes {
+        SecretBytes(bytes)
+    }
+
+    pub fn random(size: usize) -> SecretBytes {
+        let mut bytes = vec![0; size];
+        generate_random_bytes(&mut bytes);
+        SecretBytes(bytes)
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn matches(&self, other: &SecretBytes) -> bool {
+        constant_time_eq(&self.0, &other.0)
+    }
+}
+
+impl fmt::Debug for SecretBytes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SecretBytes({} bytes)", self.0.len())
+    }
+}
+
+/// Salt given to an email without account, derived from a secret key so it is the same on every request,
+/// like the stored salt of an account, and can't be told apart from a random one without the key
+pub fn derive_salt(key: &[u8], email: &str) -> [u8; 16] {
+    let mut salt = [0; 16];
+    salt.copy_from_slice(&hmac_sha256(key, email.as_bytes())[..16]);
+    salt
+}
+
+/// Argon2 parameters given to an email without account, drawn among the parameters of the accounts in the same
+/// proportions, so an account with outdated parameters doesn't stand out. `in_use` counts the accounts of each
+/// PHC string. The draw comes from the same HMAC as the salt: an email keeps its parameters on every request,
+/// and keeps `current` while the other accounts are rehashed with it, like an account.
+pub fn derive_password_params(key: &[u8], email: &str, current: &str, in_use: &[(String, usize)]) -> String {
+    let total: usize = in_use.iter().map(|(_, count)| count).sum();
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&hmac_sha256(key, email.as_bytes())[16..24]);
+    let mut draw = (u64::from_be_bytes(bytes) as f64 / 2f64.powi(64) * total as f64) as usize;
+
+    // The current parameters come first, so the share of an email is stable while they gain accounts
+    let mut ordered: Vec<&(String, usize)> = in_use.iter().collect();
+    ordered.sort_by_key(|(params, _)| (params != current, params));
+    for (params, count) in ordered {
+        if draw < *count {
+            return params.clone();
+        }
+        draw -= count;
+    }
+    current.to_string()
+}
+
+/// Bounds of the parameters accepted from a PHC string, so a server can't make the client hash forever
+const MAX_ARGON2_MEMORY: u32 = 4 * 1024 * 1024;
+const MAX_ARGON2_ITERATIONS: u32 = 64;
+const MAX_ARGON2_PARALLELISM: u32 = 64;
+
+/// Parameters of Argon2, memory is in KiB. They are written as a PHC string without salt and hash
+/// (e.g. `$argon2id$v=19$m=19456,t=2,p=1`) and stored with each account, so they can be raised later.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Argon2Params {
+    pub variant: Variant,
+    pub memory: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+}
+
+impl Argon2Params {
+    /// `argon2::Config::default()`, the parameters of the passwords hashed before they were configurable
+    pub const LEGACY: Argon2Params = Argon2Params {
+        variant: Variant::Argon2i,
+        memory: 4096,
+        iterations: 3,
+        parallelism: 1,
+    };
+
+    /// Argon2id with the given costs
+    pub fn new(memory: u32, iterations: u32, parallelism: u32) -> Result<Argon2Params, String> {
+        let params = Argon2Params {
+            variant: Variant::Argon2id,
+            memory,
+            iterations,
+            parallelism,
+        };
+        params.validate()?;
+        Ok(params)
+    }
+
+    pub fn to_phc(&self) -> String {
+        format!("${}$v={}${}", self.variant.as_lowercase_str(), Version::Version13.as_u32(), self.costs())
+    }
+
+    /// Read the parameters of a PHC string, the salt and the hash are ignored if present
+    pub fn from_phc(phc: &str) -> Result<Argon2Params, String> {
+        let invalid = || format!("Invalid Argon2 parameters: {}", phc);
+        let parts: Vec<&str> = phc.split('$').collect();
+        if parts.len() < 4 || !parts[0].is_empty() || parts[2] != format!("v={}", Version::Version13.as_u32()) {
+            return Err(invalid());
+        }
+
+        let mut params = Argon2Params {
+            variant: Variant::from_str(parts[1]).map_err(|_| invalid())?,
+            memory: 0,
+            iterations: 0,
+            parallelism: 0,
+        };
+        for cost in parts[3].split(',') {
+            let (key, value) = cost.split_once('=').ok_or_else(invalid)?;
+            let value = value.parse().map_err(|_| invalid())?;
+            match key {
+                "m" => params.memory = value,
+                "t" => params.iterations = value,
+                "p" => params.parallelism = value,
+                _ => return Err(invalid()),
+            }
+        }
+        params.validate()?;
+        Ok(params)
+    }
+
+    fn costs(&self) -> String {
+        format!("m={},t={},p={}", self.memory, self.iterations, self.parallelism)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if !(1..=MAX_ARGON2_PARALLELISM).contains(&self.parallelism)
+            || !(1..=MAX_ARGON2_ITERATIONS).contains(&self.iterations)
+            || !(8 * self.parallelism..=MAX_ARGON2_MEMORY).contains(&self.memory) {
+            return Err(format!("Argon2 parameters out of bounds: {}", self.costs()));
+        }
+        Ok(())
+    }
+}
+
+/// We assume that the hash function will always works, the parameters are validated when created
+pub fn hash_argon2(data: &str, salt: &[u8], params: &Argon2Params) -> String {
+    let config = Config {
+        variant: params.variant,
+        version: Version::Version13,
+        mem_cost: params.memory,
+        time_cost: params.iterations,
+        lanes: params.parallelism,
+        ..Config::default()
+    };
+    argon2::hash_encoded(data.as_bytes(), salt, &config).unwrap()
 }
 
 pub fn hash_sha256(data: &[u8]) -> Vec<u8> {
@@ -37,4 +225,168 @@ pub fn hashmac_sha256(data: &[u8; 16], key: &str) -> Result<Vec<u8>, String> {
     };
     mac.update(data);
     Ok(mac.finalize().into_bytes()[..].to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SALT: [u8; 16] = [1; 16];
+
+    #[test]
+    fn argon2_legacy() {
+        // The legacy parameters give the hashes of the previous versions
+        assert_eq!(hash_argon2("Test123456789$", &SALT, &Argon2Params::LEGACY),
+                   argon2::hash_encoded(b"Test123456789$", &SALT, &Config::default()).unwrap());
+        assert_eq!(Argon2Params::LEGACY.to_phc(), "$argon2i$v=19$m=4096,t=3,p=1");
+    }
+
+    #[test]
+    fn argon2_params() {
+        let params = Argon2Params::new(19456, 2, 1).unwrap();
+        let hash = hash_argon2("Test123456789$", &SALT, &params);
+        assert!(hash.starts_with("$argon2id$v=19$m=19456,t=2,p=1$"));
+
+        // Pass
+        assert_eq!(Argon2Params::from_phc(&params.to_phc()), Ok(params));
+        assert_eq!(Argon2Params::from_phc(&hash), Ok(params));
+        assert_eq!(Argon2Params::from_phc("$argon2i$v=19$m=4096,t=3,p=1"), Ok(Argon2Params::LEGACY));
+
+        // Fail
+        assert!(Argon2Params::new(0, 2, 1).is_err());
+        assert!(Argon2Params::new(19456, 0, 1).is_err());
+        assert!(Argon2Params::new(19456, 2, 0).is_err());
+        assert!(Argon2Params::new(16, 1, 4).is_err()); // less than 8 KiB per lane
+        assert!(Argon2Params::new(u32::MAX, 2, 1).is_err());
+        assert!(Argon2Params::from_phc("").is_err());
+        assert!(Argon2Params::from_phc("$argon2x$v=19$m=4096,t=3,p=1").is_err());
+        assert!(Argon2Params::from_phc("$argon2id$v=16$m=4096,t=3,p=1").is_err());
+        assert!(Argon2Params::from_phc("$argon2id$v=19$m=4096,t=3").is_err());
+        assert!(Argon2Params::from_phc("$argon2id$v=19$m=4096,t=3,p=1,x=2").is_err());
+        assert!(Argon2Params::from_phc("$argon2id$v=19$m=4096,t=1000,p=1").is_err());
+    }
+
+    #[test]
+    fn constant_time_compare() {
+        // Pass
+        assert!(constant_time_eq(b"", b""));
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(SecretBytes::new(vec![1, 2, 3]).matches(&SecretBytes::new(vec![1, 2, 3])));
+
+        // Fail
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"Secret"));
+        assert!(!constant_time_eq(b"secret", b"secret "));
+        assert!(!constant_time_eq(b"secret", b""));
+        assert!(!SecretBytes::new(vec![1, 2, 3]).matches(&SecretBytes::new(vec![1, 2])));
+        assert!(!SecretBytes::random(32).matches(&SecretBytes::random(32)));
+        assert_eq!(format!("{:?}", SecretBytes::new(vec![1, 2, 3])), "SecretBytes(3 bytes)");
+    }
+
+    #[test]
+    fn derived_salt() {
+        let key = [7; 32];
+
+        // Pass
+        assert_eq!(derive_salt(&key, "email@example.com"), derive_salt(&key, "email@example.com"));
+
+        // Fail
+        assert_ne!(derive_salt(&key, "email@example.com"), derive_salt(&key, "email@example.ch"));
+        assert_ne!(derive_salt(&key, "email@example.com"), derive_salt(&[8; 32], "email@example.com"));
+        assert_ne!(derive_salt(&key, "email@example.com"), [0; 16]);
+    }
+
+    /// Salts derived for similar emails and random salts, like the ones of the accounts, are compared
+    /// byte by byte and bit by bit. The bounds are far beyond what uniform salts give (p < 1e-6).
+    #[test]
+    fn derived_salt_distribution() {
+        const COUNT: usize = 4096;
+        let key = [7; 32];
+        let derived: Vec<[u8; 16]> = (0..COUNT)
+            .map(|i| derive_salt(&key, &format!("user{}@example.com", i)))
+            .collect();
+        let random: Vec<[u8; 16]> = (0..COUNT)
+            .map(|_| {
+                let mut salt = [0; 16];
+                generate_random_16_bytes(&mut salt);
+                salt
+            })
+            .collect();
+
+        // Every byte value is as frequent, chi-square with 255 degrees of freedom
+        let derived_bytes = byte_counts(&derived);
+        let random_bytes = byte_counts(&random);
+        let expected = (COUNT * 16) as f64 / 256.0;
+        let uniformity = |counts: &[f64; 256]| counts.iter().map(|count| (count - expected).powi(2) / expected).sum::<f64>();
+        assert!(uniformity(&derived_bytes) < 400.0);
+        assert!(uniformity(&random_bytes) < 400.0);
+
+        // Both samples have the same distribution, chi-square test of homogeneity
+        let homogeneity: f64 = derived_bytes.iter().zip(random_bytes.iter())
+            .map(|(derived, random)| (derived - random).powi(2) / (derived + random))
+            .sum();
+        assert!(homogeneity < 400.0);
+
+        // Each bit of the salt is set half of the time, within 6 standard deviations
+        let deviation = 6.0 * (COUNT as f64 / 4.0).sqrt();
+        for salts in [&derived, &random] {
+            for bit in 0..128 {
+                let ones = salts.iter().filter(|salt| salt[bit / 8] >> (bit % 8) & 1 == 1).count();
+                assert!((ones as f64 - COUNT as f64 / 2.0).abs() < deviation, "bit {} set {} times", bit, ones);
+            }
+        }
+    }
+
+    #[test]
+    fn derived_password_params() {
+        let key = [7; 32];
+        let current = Argon2Params::new(19456, 2, 1).unwrap().to_phc();
+        let legacy = Argon2Params::LEGACY.to_phc();
+        let in_use = [(legacy.clone(), 1), (current.clone(), 3)];
+
+        // Pass
+        assert_eq!(derive_password_params(&key, "email@example.com", &current, &in_use),
+                   derive_password_params(&key, "email@example.com", &current, &in_use));
+        assert_eq!(derive_password_params(&key, "email@example.com", &current, &[]), current);
+        assert_eq!(derive_password_params(&key, "email@example.com", &current, &[(legacy.clone(), 1)]), legacy);
+        assert_eq!(derive_password_params(&key, "email@example.com", &current, &[(current.clone(), 1)]), current);
+    }
+
+    /// The parameters of a real account with outdated parameters must be as frequent among the emails
+    /// without account as among the accounts, within 6 standard deviations
+    #[test]
+    fn derived_password_params_distribution() {
+        const COUNT: usize = 4096;
+        let key = [7; 32];
+        let current = Argon2Params::new(19456, 2, 1).unwrap().to_phc();
+        let legacy = Argon2Params::LEGACY.to_phc();
+        let emails: Vec<String> = (0..COUNT).map(|i| format!("user{}@example.com", i)).collect();
+
+        let mut previous: Option<Vec<bool>> = None;
+        for legacy_accounts in [900, 500, 100, 0] {
+            let in_use = [(legacy.clone(), legacy_accounts), (current.clone(), 1000 - legacy_accounts)];
+            let outdated: Vec<bool> = emails.iter()
+                .map(|email| derive_password_params(&key, email, &current, &in_use) == legacy)
+                .collect();
+
+            let share = legacy_accounts as f64 / 1000.0;
+            let count = outdated.iter().filter(|outdated| **outdated).count() as f64;
+            let deviation = 6.0 * (COUNT as f64 * share * (1.0 - share)).sqrt();
+            assert!((count - COUNT as f64 * share).abs() <= deviation, "{} outdated for {}", count, share);
+
+            // An email never gets outdated parameters back when accounts are rehashed, like an account
+            if let Some(previous) = previous {
+                assert!(outdated.iter().zip(previous.iter()).all(|(now, before)| !now || *before));
+            }
+            previous = Some(outdated);
+        }
+    }
+
+    fn byte_counts(salts: &[[u8; 16]]) -> [f64; 256] {
+        let mut counts = [0.0; 256];
+        for byte in salts.iter().flatten() {
+            counts[*byte as usize] += 1.0;
+        }
+        counts
+    }
 }
\ No newline at end of file
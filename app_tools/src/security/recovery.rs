@@ -0,0 +1,66 @@
+use crate::security::crypto::{generate_random_bytes, hash_sha256, SecretBytes};
+use crate::security::totp::base32_encode;
+
+/// Codes given to the user at once, each one can be used a single time
+pub const CODE_COUNT: usize = 10;
+/// 80 random bits, written as 16 base32 characters
+const CODE_SIZE: usize = 10;
+const GROUP_SIZE: usize = 4;
+
+/// New codes, formatted for the user as `XXXX-XXXX-XXXX-XXXX`
+pub fn generate_codes() -> Vec<String> {
+    (0..CODE_COUNT).map(|_| {
+        let mut bytes = [0; CODE_SIZE];
+        generate_random_bytes(&mut bytes);
+        let encoded = base32_encode(&bytes);
+        encoded.as_bytes()
+            .chunks(GROUP_SIZE)
+            .map(|group| String::from_utf8_lossy(group))
+            .collect::<Vec<_>>()
+            .join("-")
+    }).collect()
+}
+
+/// The case, the dashes and the spaces typed by the user don't matter
+pub fn normalize(code: &str) -> String {
+    code.chars()
+        .filter(|c| *c != '-' && !c.is_whitespace())
+        .map(|c| c.to_ascii_uppercase())
+        .collect()
+}
+
+/// Only the hashes are stored, the codes are random enough for a fast hash
+pub fn hash_code(code: &str) -> SecretBytes {
+    SecretBytes::new(hash_sha256(normalize(code).as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recovery_generate() {
+        let codes = generate_codes();
+        assert_eq!(codes.len(), CODE_COUNT);
+        for code in &codes {
+            assert_eq!(code.len(), 19);
+            assert_eq!(code.matches('-').count(), 3);
+            assert_eq!(normalize(code).len(), 16);
+        }
+
+        // Codes are random
+        assert_ne!(codes[0], codes[1]);
+        assert_ne!(generate_codes(), codes);
+    }
+
+    #[test]
+    fn recovery_hash() {
+        // Pass
+        assert!(hash_code("ABCD-EFGH-IJKL-MNOP").matches(&hash_code("abcdefghijklmnop")));
+        assert!(hash_code("ABCD-EFGH-IJKL-MNOP").matches(&hash_code(" abcd efgh ijkl mnop ")));
+
+        // Fail
+        assert!(!hash_code("ABCD-EFGH-IJKL-MNOP").matches(&hash_code("ABCD-EFGH-IJKL-MNOQ")));
+        assert!(!hash_code("ABCD-EFGH-IJKL-MNOP").matches(&hash_code("")));
+    }
+}
\ No newline at end of file
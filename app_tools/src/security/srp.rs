@@ -0,0 +1,111 @@
+use ::srp::client::{SrpClient, SrpClientVerifier};
+use ::srp::groups::G_2048;
+use ::srp::server::{SrpServer, SrpServerVerifier};
+use sha2::Sha256;
+use crate::security::crypto::generate_random_bytes;
+
+/// Size of the secret ephemeral values `a` and `b`
+const SECRET_SIZE: usize = 32;
+
+/// The password is first hashed with Argon2 by the client (`hash_argon2` with the salt and the parameters of the account),
+/// this hash is the SRP password, so the verifier can't be brute forced faster than Argon2.
+/// The email is the SRP identity.
+/// # Returns
+/// * `Vec<u8>` - The verifier stored by the server, it can't be used to log in
+pub fn compute_verifier(email: &str, password_hash: &str, salt: &[u8]) -> Vec<u8> {
+    SrpClient::<Sha256>::new(&G_2048).compute_verifier(email.as_bytes(), password_hash.as_bytes(), salt)
+}
+
+/// Random secret ephemeral value, `a` for the client and `b` for the server.
+/// It must be used for a single login.
+pub fn generate_secret() -> Vec<u8> {
+    let mut secret = vec![0; SECRET_SIZE];
+    generate_random_bytes(&mut secret);
+    secret
+}
+
+/// `A` sent by the client with its email
+pub fn client_public(a: &[u8]) -> Vec<u8> {
+    SrpClient::<Sha256>::new(&G_2048).compute_public_ephemeral(a)
+}
+
+/// `B` sent by the server with the salt
+pub fn server_public(b: &[u8], verifier: &[u8]) -> Vec<u8> {
+    SrpServer::<Sha256>::new(&G_2048).compute_public_ephemeral(b, verifier)
+}
+
+/// Compute the proof of the client (`proof()`), the result is then used to check the proof of the server
+/// # Errors
+/// * `String` - The server sent an invalid `B`
+pub fn client_verifier(a: &[u8],
+                       email: &str,
+                       password_hash: &str,
+                       salt: &[u8],
+                       b_pub: &[u8]) -> Result<SrpClientVerifier<Sha256>, String> {
+    SrpClient::<Sha256>::new(&G_2048)
+        .process_reply(a, email.as_bytes(), password_hash.as_bytes(), salt, b_pub)
+        .map_err(|e| e.to_string())
+}
+
+/// Check the proof of the client, `b` must be the secret used for `server_public`
+/// # Returns
+/// * `Vec<u8>` - The proof of the server, sent back so the client knows it talks to the right server
+/// # Errors
+/// * `String` - The client sent an invalid `A` or a wrong proof
+pub fn verify_client(b: &[u8], verifier: &[u8], a_pub: &[u8], proof: &[u8]) -> Result<Vec<u8>, String> {
+    let server: SrpServerVerifier<Sha256> = SrpServer::<Sha256>::new(&G_2048)
+        .process_reply(b, verifier, a_pub)
+        .map_err(|e| e.to_string())?;
+    server.verify_client(proof).map_err(|e| e.to_string())?;
+    Ok(server.proof().to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::security::crypto::{hash_argon2, Argon2Params};
+
+    const EMAIL: &str = "email@example.com";
+    const SALT: [u8; 16] = [7; 16];
+
+    fn login(password: &str, verifier: &[u8]) -> (SrpClientVerifier<Sha256>, Result<Vec<u8>, String>) {
+        let a = generate_secret();
+        let b = generate_secret();
+        let b_pub = server_public(&b, verifier);
+        let client = client_verifier(&a, EMAIL, &hash_argon2(password, &SALT, &Argon2Params::LEGACY), &SALT, &b_pub).unwrap();
+        let server = verify_client(&b, verifier, &client_public(&a), client.proof());
+        (client, server)
+    }
+
+    #[test]
+    fn srp_login() {
+        let verifier = compute_verifier(EMAIL, &hash_argon2("Test123456789$", &SALT, &Argon2Params::LEGACY), &SALT);
+
+        // Pass
+        let (client, server) = login("Test123456789$", &verifier);
+        assert!(client.verify_server(&server.unwrap()).is_ok());
+
+        // Fail
+        let (_, server) = login("Test123456789%", &verifier);
+        assert!(server.is_err());
+    }
+
+    #[test]
+    fn srp_verifier() {
+        let hash = hash_argon2("Test123456789$", &SALT, &Argon2Params::LEGACY);
+
+        // The verifier depends on the identity and the salt, and isn't the password hash
+        assert_eq!(compute_verifier(EMAIL, &hash, &SALT), compute_verifier(EMAIL, &hash, &SALT));
+        assert_ne!(compute_verifier(EMAIL, &hash, &SALT), compute_verifier("other@example.com", &hash, &SALT));
+        assert_ne!(compute_verifier(EMAIL, &hash, &SALT), compute_verifier(EMAIL, &hash, &[8; 16]));
+        assert_ne!(compute_verifier(EMAIL, &hash, &SALT), hash.as_bytes());
+    }
+
+    #[test]
+    fn srp_invalid_public() {
+        // A multiple of N would make the shared secret predictable
+        let verifier = compute_verifier(EMAIL, "hash", &SALT);
+        assert!(verify_client(&generate_secret(), &verifier, &[0], &[0; 32]).is_err());
+        assert!(client_verifier(&generate_secret(), EMAIL, "hash", &SALT, &[0]).is_err());
+    }
+}
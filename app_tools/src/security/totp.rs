@@ -0,0 +1,119 @@
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+use crate::security::crypto::{constant_time_eq, SecretBytes};
+
+type HmacSha1 = Hmac<Sha1>;
+
+/// Parameters understood by every authenticator app (RFC 6238 defaults)
+pub const SECRET_SIZE: usize = 20;
+pub const DIGITS: u32 = 6;
+pub const PERIOD: u64 = 30;
+
+const BASE32_ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+pub fn generate_secret() -> SecretBytes {
+    SecretBytes::random(SECRET_SIZE)
+}
+
+/// Time step of a UNIX timestamp in seconds
+pub fn time_step(timestamp: u64) -> u64 {
+    timestamp / PERIOD
+}
+
+/// HOTP value (RFC 4226) of a time step, with `DIGITS` digits
+pub fn code(secret: &[u8], step: u64) -> String {
+    // HMAC accepts keys of any size
+    let mut mac = HmacSha1::new_from_slice(secret).unwrap();
+    mac.update(&step.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+
+    // Dynamic truncation
+    let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+    let binary = u32::from_be_bytes([hash[offset] & 0x7f, hash[offset + 1], hash[offset + 2], hash[offset + 3]]);
+    format!("{:0width$}", binary % 10u32.pow(DIGITS), width = DIGITS as usize)
+}
+
+/// Check a code against the steps around the current one, to allow for clock drift
+/// # Arguments
+/// * `skew` - Number of steps accepted before and after the current one
+/// # Returns
+/// * `Option<u64>` - The step of the code, `None` if it doesn't match.
+///   The caller must refuse steps already used to prevent replays.
+pub fn verify(secret: &[u8], code_to_test: &str, timestamp: u64, skew: u64) -> Option<u64> {
+    let current = time_step(timestamp);
+    (current.saturating_sub(skew)..=current.saturating_add(skew))
+        .find(|step| constant_time_eq(code(secret, *step).as_bytes(), code_to_test.as_bytes()))
+}
+
+/// URI for authenticator apps, usually shown as a QR code
+/// (https://github.com/google/google-authenticator/wiki/Key-Uri-Format)
+pub fn otpauth_uri(secret: &[u8], issuer: &str, account: &str) -> String {
+    format!("otpauth://totp/{}:{}?secret={}&issuer={}&algorithm=SHA1&digits={}&period={}",
+            encode_uri(issuer), encode_uri(account), base32_encode(secret), encode_uri(issuer), DIGITS, PERIOD)
+}
+
+/// Base32 without padding (RFC 4648), the format of the secrets typed in authenticator apps
+pub fn base32_encode(data: &[u8]) -> String {
+    let mut encoded = String::new();
+    for chunk in data.chunks(5) {
+        let mut buffer = [0u8; 5];
+        buffer[..chunk.len()].copy_from_slice(chunk);
+        let bits = buffer.iter().fold(0u64, |bits, byte| (bits << 8) | *byte as u64);
+        let chars = (chunk.len() * 8).div_ceil(5);
+        for i in 0..chars {
+            let index = (bits >> (35 - i * 5)) & 0x1f;
+            encoded.push(BASE32_ALPHABET[index as usize] as char);
+        }
+    }
+    encoded
+}
+
+fn encode_uri(value: &str) -> String {
+    value.bytes().map(|byte| match byte {
+        b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'@' => (byte as char).to_string(),
+        _ => format!("%{:02X}", byte),
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Secret of the test vectors of RFC 6238 for SHA-1
+    const SECRET: &[u8] = b"12345678901234567890";
+
+    #[test]
+    fn totp_code() {
+        // Last 6 digits of the RFC 6238 test vectors
+        assert_eq!(code(SECRET, time_step(59)), "287082");
+        assert_eq!(code(SECRET, time_step(1111111109)), "081804");
+        assert_eq!(code(SECRET, time_step(1234567890)), "005924");
+        assert_eq!(code(SECRET, time_step(20000000000)), "353130");
+    }
+
+    #[test]
+    fn totp_verify() {
+        let timestamp = 1111111109;
+
+        // Pass
+        assert_eq!(verify(SECRET, "081804", timestamp, 0), Some(time_step(timestamp)));
+        assert_eq!(verify(SECRET, "081804", timestamp + PERIOD, 1), Some(time_step(timestamp)));
+        assert_eq!(verify(SECRET, "081804", timestamp - PERIOD, 1), Some(time_step(timestamp)));
+
+        // Fail
+        assert_eq!(verify(SECRET, "081804", timestamp + PERIOD, 0), None);
+        assert_eq!(verify(SECRET, "081804", timestamp + 2 * PERIOD, 1), None);
+        assert_eq!(verify(SECRET, "081805", timestamp, 1), None);
+        assert_eq!(verify(SECRET, "", timestamp, 1), None);
+    }
+
+    #[test]
+    fn totp_uri() {
+        assert_eq!(base32_encode(SECRET), "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ");
+        assert_eq!(base32_encode(b"f"), "MY");
+        assert_eq!(base32_encode(b"fooba"), "MZXW6YTB");
+        assert_eq!(otpauth_uri(b"fooba", "SEC Labo", "email@example.com"),
+                   "otpauth://totp/SEC%20Labo:email@example.com?secret=MZXW6YTB&issuer=SEC%20Labo\
+                   &algorithm=SHA1&digits=6&period=30");
+    }
+}
@@ -1,17 +1,48 @@
-use serde::{Serialize, Deserialize};
 use std::error::Error;
-use app_tools::communication::data::ChangeTwoFA;
+use app_tools::communication::data::{ActionData,
+                                     ActionRequest,
+                                     AddYubiKeyData,
+                                     ChallengeData,
+                                     ChangeTwoFA,
+                                     RecoveryCodesData,
+                                     ServerResponse,
+                                     TotpCodeData,
+                                     TotpSecretData,
+                                     YubiKeyInfo,
+                                     YubiKeyLabelData,
+                                     YubiKeysData};
+use app_tools::communication::status::StatusCode;
+use chrono::DateTime;
+use app_tools::security::challenge::ChallengePurpose;
+use qrcode::QrCode;
+use qrcode::render::unicode;
 use strum::IntoEnumIterator;
 use strum_macros::{EnumString, EnumIter};
+use crate::authentication_tools::{generate_yubikey_signature, show_recovery_codes};
 use crate::connection::Connection;
+use crate::handlers::{ask_key_label, ask_totp_code};
+use crate::yubi::Yubi;
 
 /// `Action` enum is used to perform logged operations:
 /// -   Enable/Disable 2fa authentication
-#[derive(Serialize, Deserialize, Debug, EnumString, EnumIter)]
+/// -   Enrol an authenticator app (TOTP)
+/// -   Generate new recovery codes
+/// -   List, add and remove YubiKeys
+#[derive(Debug, EnumString, EnumIter)]
 pub enum Action {
     #[strum(serialize = "Enable/Disable 2FA", serialize = "1")]
     Switch2FA,
-    #[strum(serialize = "Exit", serialize = "2")]
+    #[strum(serialize = "Enrol authenticator app", serialize = "2")]
+    EnrolTotp,
+    #[strum(serialize = "Generate recovery codes", serialize = "3")]
+    GenerateRecoveryCodes,
+    #[strum(serialize = "List yubikeys", serialize = "4")]
+    ListYubiKeys,
+    #[strum(serialize = "Add yubikey", serialize = "5")]
+    AddYubiKey,
+    #[strum(serialize = "Remove yubikey", serialize = "6")]
+    RemoveYubiKey,
+    #[strum(serialize = "Exit", serialize = "7")]
     Logout
 }
 
@@ -21,13 +52,78 @@ impl Action {
         for i in 1..=actions.len() { println!("{}.\t{:?}", i, actions.next().unwrap()); }
     }
 
-    pub fn perform(&self, connection: &mut Connection) -> Result<bool, Box<dyn Error>> {
-        connection.send(self)?;
-
+    /// # Returns
+    /// * `bool` - `false` once the session is over, after a logout or if it has expired
+    pub fn perform(&self, connection: &mut Connection, token: &[u8]) -> Result<bool, Box<dyn Error>> {
         match self {
-            Action::Switch2FA => Action::switch_2fa(connection),
-            Action::Logout => Ok(false)
+            Action::Switch2FA => {
+                if !Action::send_request(connection, token, ActionRequest::Switch2FA)? {
+                    return Ok(false);
+                }
+                Action::switch_2fa(connection)
+            },
+            Action::EnrolTotp => {
+                if !Action::send_request(connection, token, ActionRequest::EnrolTotp)? {
+                    return Ok(false);
+                }
+                Action::enrol_totp(connection)
+            },
+            Action::GenerateRecoveryCodes => {
+                if !Action::send_request(connection, token, ActionRequest::GenerateRecoveryCodes)? {
+                    return Ok(false);
+                }
+                Action::generate_recovery_codes(connection)
+            },
+            Action::ListYubiKeys => {
+                if !Action::send_request(connection, token, ActionRequest::ListYubiKeys)? {
+                    return Ok(false);
+                }
+                let yubikeys_data :YubiKeysData = connection.receive()?;
+                Action::show_yubikeys(&yubikeys_data.keys);
+                Ok(true)
+            },
+            Action::AddYubiKey => {
+                if !Action::send_request(connection, token, ActionRequest::AddYubiKey)? {
+                    return Ok(false);
+                }
+                Action::add_yubikey(connection)
+            },
+            Action::RemoveYubiKey => {
+                if !Action::send_request(connection, token, ActionRequest::RemoveYubiKey)? {
+                    return Ok(false);
+                }
+                Action::remove_yubikey(connection)
+            },
+            Action::Logout => {
+                connection.send(ActionData {
+                    token: token.to_vec(),
+                    request: ActionRequest::Logout,
+                })?;
+                Ok(false)
+            }
+        }
+    }
+
+    /// The server checks the session token before doing anything
+    /// # Returns
+    /// * `bool` - `false` if the session has expired or has been revoked
+    /// # Errors
+    /// * `StatusCode::SecondFactorRequired` - The session was opened without the second factor, it can go on
+    fn send_request(connection: &mut Connection, token: &[u8], request: ActionRequest) -> Result<bool, Box<dyn Error>> {
+        connection.send(ActionData {
+            token: token.to_vec(),
+            request,
+        })?;
+
+        let response :ServerResponse = connection.receive()?;
+        if response.status == StatusCode::SecondFactorRequired {
+            return Err(response.status.into());
         }
+        if !response.status.is_success() {
+            eprintln!("{}\n", response.status);
+            return Ok(false);
+        }
+        Ok(true)
     }
 
     fn switch_2fa(connection: &mut Connection) -> Result<bool, Box<dyn Error>> {
@@ -41,4 +137,95 @@ impl Action {
 
         Ok(true)
     }
+
+    /// The secret is shown as a QR code to scan, or as an URI for apps without a camera
+    fn enrol_totp(connection: &mut Connection) -> Result<bool, Box<dyn Error>> {
+        let secret_data :TotpSecretData = connection.receive()?;
+
+        let qr_code = QrCode::new(&secret_data.uri)?
+            .render::<unicode::Dense1x2>()
+            .dark_color(unicode::Dense1x2::Light)
+            .light_color(unicode::Dense1x2::Dark)
+            .build();
+        println!("Scan this QR code with your authenticator app:\n{}", qr_code);
+        println!("Or enter this URI: {}\n", secret_data.uri);
+
+        // The first code confirms that the app has the secret
+        connection.send(TotpCodeData {
+            code: ask_totp_code(),
+        })?;
+
+        let response :ServerResponse = connection.receive()?;
+        if response.status.is_success() {
+            println!("{}", response.status);
+        } else {
+            eprintln!("{}\n", response.status);
+        }
+        Ok(true)
+    }
+
+    fn generate_recovery_codes(connection: &mut Connection) -> Result<bool, Box<dyn Error>> {
+        let recovery_data :RecoveryCodesData = connection.receive()?;
+        println!("The previous recovery codes can't be used anymore");
+        show_recovery_codes(&recovery_data.codes);
+        Ok(true)
+    }
+
+    /// A registered key signs the challenge first, then a new key is generated on the other one
+    fn add_yubikey(connection: &mut Connection) -> Result<bool, Box<dyn Error>> {
+        let challenge_data :ChallengeData = connection.receive()?;
+
+        Yubi::wait_for("Insert a yubikey already registered");
+        let signature = generate_yubikey_signature(&challenge_data.challenge, ChallengePurpose::AddYubiKey)?;
+
+        // Generating a key replaces the one of the slot, so it must not be done on the registered key
+        Yubi::wait_for("Remove it and insert the new yubikey, its authentication key will be replaced");
+        let label = ask_key_label();
+        let (public_key, attestation) = Yubi::generate_keys()?;
+        connection.send(AddYubiKeyData {
+            label,
+            public_key,
+            signature,
+            attestation,
+        })?;
+
+        Action::show_response(connection)
+    }
+
+    fn remove_yubikey(connection: &mut Connection) -> Result<bool, Box<dyn Error>> {
+        let yubikeys_data :YubiKeysData = connection.receive()?;
+        Action::show_yubikeys(&yubikeys_data.keys);
+
+        connection.send(YubiKeyLabelData {
+            label: ask_key_label(),
+        })?;
+
+        Action::show_response(connection)
+    }
+
+    fn show_response(connection: &mut Connection) -> Result<bool, Box<dyn Error>> {
+        let response :ServerResponse = connection.receive()?;
+        if response.status.is_success() {
+            println!("{}\n", response.status);
+        } else {
+            eprintln!("{}\n", response.status);
+        }
+        Ok(true)
+    }
+
+    fn show_yubikeys(keys: &[YubiKeyInfo]) {
+        for key in keys {
+            let last_used = key.last_used.map_or(String::from("never"), format_date);
+            let serial = key.serial.map_or(String::from("not attested"), |serial| format!("serial {}", serial));
+            println!("- {}\t(added {}, last used {}, {})", key.label, format_date(key.created), last_used, serial);
+        }
+        println!();
+    }
+}
+
+/// UTC date of a UNIX timestamp, e.g. `2024-01-31 12:00 UTC`
+fn format_date(timestamp: u64) -> String {
+    i64::try_from(timestamp).ok()
+        .and_then(|timestamp| DateTime::from_timestamp(timestamp, 0))
+        .map_or(String::from("unknown"), |date| date.format("%Y-%m-%d %H:%M UTC").to_string())
 }
\ No newline at end of file
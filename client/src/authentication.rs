@@ -1,10 +1,11 @@
-use serde::{Serialize, Deserialize};
 use std::error::Error;
 use strum::IntoEnumIterator;
 use strum_macros::{EnumString, EnumIter};
 
 use app_tools::communication::data::*;
-use app_tools::security::crypto::{hash_argon2, hashmac_sha256};
+use app_tools::security::challenge::ChallengePurpose;
+use app_tools::security::crypto::{hash_argon2, Argon2Params};
+use app_tools::security::srp;
 
 use crate::connection::Connection;
 use crate::authentication_tools::*;
@@ -15,7 +16,8 @@ use crate::yubi::Yubi;
 /// -   User
 /// -   Registration
 /// -   Password Reset
-#[derive(Serialize, Deserialize, Debug, EnumString, EnumIter)]
+/// -   Email verification, when the connection of the registration or the reset was lost
+#[derive(Debug, EnumString, EnumIter)]
 pub enum Authenticate {
     #[strum(serialize = "Authenticate", serialize = "1")]
     Authenticate,
@@ -23,7 +25,9 @@ pub enum Authenticate {
     Register,
     #[strum(serialize = "Reset password", serialize = "3")]
     Reset,
-    #[strum(serialize = "Exit", serialize = "4")]
+    #[strum(serialize = "Verify email", serialize = "4")]
+    Verify,
+    #[strum(serialize = "Exit", serialize = "5")]
     Exit
 }
 
@@ -33,83 +37,173 @@ impl Authenticate {
         for i in 1..=actions.len() { println!("{}.\t{:?}", i, actions.next().unwrap()); }
     }
 
-    pub fn perform(&self, connection: &mut Connection) -> Result<(), Box<dyn Error>> {
-        connection.send(self)?;
-
+    /// # Returns
+    /// * `SessionData` - The token for the actions and the ticket to resume the session if the connection drops
+    pub fn perform(&self, connection: &mut Connection) -> Result<SessionData, Box<dyn Error>> {
+        // The server first tells whether it accepts the request, it may be rate limited
         match self {
-            Authenticate::Authenticate => Authenticate::authenticate(connection),
-            Authenticate::Register => Authenticate::register(connection),
-            Authenticate::Reset => Authenticate::reset_password(connection),
+            Authenticate::Authenticate => {
+                connection.send(AuthenticateRequest::Authenticate)?;
+                handle_server_response(connection)?;
+                Authenticate::authenticate(connection)?
+            },
+            Authenticate::Register => {
+                connection.send(AuthenticateRequest::Register)?;
+                handle_server_response(connection)?;
+                Authenticate::register(connection)?
+            },
+            Authenticate::Reset => {
+                connection.send(AuthenticateRequest::Reset)?;
+                handle_server_response(connection)?;
+                Authenticate::reset_password(connection)?
+            },
+            Authenticate::Verify => {
+                connection.send(AuthenticateRequest::Verify)?;
+                handle_server_response(connection)?;
+                Authenticate::verify(connection)?
+            },
             Authenticate::Exit => {
+                connection.send(AuthenticateRequest::Exit)?;
                 println!("Exiting..."); std::process::exit(0);
             }
         }
+
+        // Every successful login is followed by the session
+        connection.receive()
+    }
+
+    /// Log in again on a new connection with the ticket of the lost one,
+    /// the password and the second factor aren't asked again
+    /// # Returns
+    /// * `SessionData` - The session with a new ticket, the given one can't be used anymore
+    pub fn resume(connection: &mut Connection, ticket: &[u8]) -> Result<SessionData, Box<dyn Error>> {
+        connection.send(AuthenticateRequest::Resume)?;
+        handle_server_response(connection)?;
+        connection.send(TicketData {
+            ticket: ticket.to_vec(),
+        })?;
+
+        handle_server_response(connection)?;
+
+        connection.receive()
     }
 
     fn register(connection: &mut Connection) -> Result<(), Box<dyn Error>> {
         println!("<< Please register yourself >>");
 
+        let params_data :PasswordParamsData = connection.receive()?;
+        let params = Argon2Params::from_phc(&params_data.password_params)?;
+
+        let email = ask_email();
+        let (salt, verifier) = generate_verifier(&email, &ask_password(), &params);
+        let (public_yubikey, attestation) = Yubi::generate_keys()?;
+
         // Send datas to server
-        connection.send(&RegisterData {
-            email: ask_email(),
-            password: ask_password(),
-            public_yubikey: Yubi::generate_keys()?,
+        connection.send(RegisterData {
+            email,
+            salt,
+            verifier,
+            public_yubikey,
+            yubikey_label: ask_key_label(),
+            attestation,
         })?;
 
         handle_server_response(connection)?;
 
-        // Send email uuid confirmation value
-        connection.send(&UUIDData {
+        // Send email uuid confirmation value, if the connection drops it can be sent with `Verify` later
+        connection.send(UUIDData {
             uuid: ask_uuid(),
         })?;
 
+        Authenticate::finish_registration(connection)
+    }
+
+    fn finish_registration(connection: &mut Connection) -> Result<(), Box<dyn Error>> {
         handle_server_response(connection)?;
 
+        let recovery_data :RecoveryCodesData = connection.receive()?;
+        show_recovery_codes(&recovery_data.codes);
+
         Ok(())
     }
 
+    /// Send the email token of a registration or a reset started on a lost connection
+    fn verify(connection: &mut Connection) -> Result<(), Box<dyn Error>> {
+        println!("<< Verify email >>");
+
+        let purpose = ask_verification_purpose();
+        let email = ask_email();
+        connection.send(VerificationData {
+            purpose,
+            email: email.clone(),
+            uuid: ask_uuid(),
+        })?;
+
+        match purpose {
+            VerificationPurpose::Registration => Authenticate::finish_registration(connection),
+            VerificationPurpose::PasswordReset => Authenticate::finish_reset(connection, &email),
+        }
+    }
+
     fn authenticate(connection: &mut Connection) -> Result<(), Box<dyn Error>> {
         println!("<< Please authenticate yourself >>");
 
         // Send datas to server
-        connection.send(&EmailData {
-            email: ask_email(),
+        let email = ask_email();
+        let a = srp::generate_secret();
+        connection.send(SrpStartData {
+            email: email.clone(),
+            a_pub: srp::client_public(&a),
         })?;
 
         let password_input = ask_password();
 
         // Receive challenge
-        let mut challenge_data: ChallengeWithSaltData = connection.receive()?;
-
-        // Creating the response for the challenge
-        let hash_password = hash_argon2(&password_input, &mut challenge_data.salt);
-        match hashmac_sha256(&challenge_data.challenge, &hash_password) {
-            Ok(response_hash) => {
-                // Send response datas to server
-                connection.send(&ResponseData {
-                    response: response_hash,
-                })?;
-            },
-            Err(error) => {
-                return Err(error.into());
-            },
-        }
+        let challenge_data: SrpChallengeData = connection.receive()?;
+
+        // The parameters come from the server, they are checked before hashing
+        let params = Argon2Params::from_phc(&challenge_data.password_params)?;
+
+        // Creating the proof, the password is hashed with the salt and the parameters of the account first
+        let hash_password = hash_argon2(&password_input, &challenge_data.salt, &params);
+        let srp_client = srp::client_verifier(&a, &email, &hash_password,
+                                              &challenge_data.salt, &challenge_data.b_pub)?;
+
+        // Send response datas to server
+        connection.send(ResponseData {
+            response: srp_client.proof().to_vec(),
+        })?;
 
         // Handle server response and if two FA is needed
         let serveur_response :ServerResponseTwoFA = connection.receive()?;
-        if !serveur_response.success {
-            return Err(format!("{}", serveur_response.message).into());
-        } else if !serveur_response.two_fa {
-            return Ok(());
+        if !serveur_response.status.is_success() {
+            return Err(serveur_response.status.into());
         }
 
-        // Second factor authentification
-        // We use same challenge than before (for the hmac part)
-        connection.send(&ResponseData {
-            response: generate_yubikey_signature(&challenge_data.challenge)?
-        })?;
+        // The server proves that it knows the verifier
+        if srp_client.verify_server(&serveur_response.proof).is_err() {
+            return Err("The server could not prove it knows the password verifier".into());
+        }
 
-        handle_server_response(connection)?;
+        if serveur_response.two_fa {
+            // Second factor authentification, with one of the factors of the user
+            // We use same challenge than before for the YubiKey
+            let factor = ask_second_factor(&serveur_response.factors);
+            connection.send(generate_second_factor_response(factor, &challenge_data.challenge, ChallengePurpose::Login)?)?;
+
+            handle_server_response(connection)?;
+        }
+
+        // The account was hashed with outdated parameters, the server gets a new verifier of the same password
+        let params_data :PasswordParamsData = connection.receive()?;
+        let new_params = Argon2Params::from_phc(&params_data.password_params)?;
+        if new_params != params {
+            let (salt, verifier) = generate_verifier(&email, &password_input, &new_params);
+            connection.send(VerifierData {
+                salt,
+                verifier,
+            })?;
+        }
 
         Ok(())
     }
@@ -118,32 +212,44 @@ impl Authenticate {
         println!("<< Reset password >>");
 
         // Send email to server
-        connection.send(&EmailData {
-            email: ask_email(),
+        let email = ask_email();
+        connection.send(EmailData {
+            email: email.clone(),
         })?;
 
         handle_server_response(connection)?;
 
-        // Get challenge and send response to it
+        // Get challenge and send response to it, a recovery code can be used if the YubiKey is lost
         let challenge_data :ChallengeData = connection.receive()?;
-        connection.send(&ResponseData {
-            response: generate_yubikey_signature(&challenge_data.challenge)?
-        })?;
+        let factor = ask_second_factor(&[SecondFactorType::YubiKey, SecondFactorType::RecoveryCode]);
+        connection.send(generate_second_factor_response(factor,
+                                                        &challenge_data.challenge,
+                                                        ChallengePurpose::PasswordReset)?)?;
 
         handle_server_response(connection)?;
 
-        // Send email uuid confirmation value
-        connection.send(&UUIDData {
+        // Send email uuid confirmation value, if the connection drops it can be sent with `Verify` later
+        connection.send(UUIDData {
             uuid: ask_uuid(),
         })?;
 
+        Authenticate::finish_reset(connection, &email)
+    }
+
+    fn finish_reset(connection: &mut Connection, email: &str) -> Result<(), Box<dyn Error>> {
         handle_server_response(connection)?;
 
-        // Send new password
-        connection.send(&PasswordData {
-            password: ask_password(),
+        // Send new password, derived with the Argon2 parameters of the server
+        let params_data :PasswordParamsData = connection.receive()?;
+        let params = Argon2Params::from_phc(&params_data.password_params)?;
+        let (salt, verifier) = generate_verifier(email, &ask_password(), &params);
+        connection.send(VerifierData {
+            salt,
+            verifier,
         })?;
 
+        handle_server_response(connection)?;
+
         Ok(())
     }
 }
\ No newline at end of file
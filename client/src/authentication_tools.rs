@@ -1,17 +1,53 @@
 use std::error::Error;
-use app_tools::security::crypto::hash_sha256;
-use app_tools::communication::data::ServerResponse;
+use app_tools::security::crypto::{generate_random_16_bytes, hash_argon2, hash_sha256, Argon2Params};
+use app_tools::security::challenge::{Challenge, ChallengePurpose};
+use app_tools::security::srp;
+use app_tools::communication::data::{SecondFactorResponse, SecondFactorType, ServerResponse};
+use crate::config::CONFIG;
 use crate::yubi::Yubi;
 use crate::connection::Connection;
+use crate::handlers::{ask_recovery_code, ask_totp_code};
 
-pub fn generate_yubikey_signature(challenge: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
-    Ok(Yubi::sign(&hash_sha256(challenge))?.to_vec())
+/// The challenge is only signed if it is meant for this step and for the server of the config (`SERVER_ID`),
+/// so a signature can't be used in another flow or relayed to another server
+pub fn generate_yubikey_signature(challenge: &Challenge, purpose: ChallengePurpose) -> Result<Vec<u8>, Box<dyn Error>> {
+    challenge.check_origin(purpose, &CONFIG.server_id)?;
+    Ok(Yubi::sign(&hash_sha256(&challenge.to_bytes()))?.to_vec())
+}
+
+/// Answer the second factor step with the factor chosen by the user
+pub fn generate_second_factor_response(factor: SecondFactorType,
+                                       challenge: &Challenge,
+                                       purpose: ChallengePurpose) -> Result<SecondFactorResponse, Box<dyn Error>> {
+    Ok(match factor {
+        SecondFactorType::YubiKey => SecondFactorResponse::YubiKey(generate_yubikey_signature(challenge, purpose)?),
+        SecondFactorType::Totp => SecondFactorResponse::Totp(ask_totp_code()),
+        SecondFactorType::RecoveryCode => SecondFactorResponse::RecoveryCode(ask_recovery_code()),
+    })
+}
+
+/// The codes are only shown once, the server keeps their hashes
+pub fn show_recovery_codes(codes: &[String]) {
+    println!("Recovery codes, each one can be used once in place of the YubiKey:");
+    for code in codes {
+        println!("\t{}", code);
+    }
+    println!("Keep them in a safe place\n");
+}
+
+/// Derive the SRP verifier of a password with a new salt and the Argon2 parameters of the server,
+/// the server never gets the password
+pub fn generate_verifier(email: &str, password: &str, params: &Argon2Params) -> ([u8; 16], Vec<u8>) {
+    let mut salt = [0; 16];
+    generate_random_16_bytes(&mut salt);
+    let verifier = srp::compute_verifier(email, &hash_argon2(password, &salt, params), &salt);
+    (salt, verifier)
 }
 
 pub fn handle_server_response(connection: &mut Connection) -> Result<(), Box<dyn Error>> {
     let return_message: ServerResponse = connection.receive()?;
-    if !return_message.success {
-        return Err(format!("{}", return_message.message).into());
+    if !return_message.status.is_success() {
+        return Err(return_message.status.into());
     }
     Ok(())
 }
\ No newline at end of file
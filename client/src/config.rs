@@ -0,0 +1,118 @@
+use clap::Parser;
+use envfile::EnvFile;
+use std::error::Error;
+use std::path::Path;
+use std::time::Duration;
+use app_tools::communication::framing::DEFAULT_MAX_FRAME_SIZE;
+use app_tools::config::{parse_bool, parse_number, ConfigRef};
+
+const DEFAULT_CONFIG_PATH: &str = "./.env";
+
+/// Settings of the client, loaded from the default env file if `Config::init` hasn't been called
+pub static CONFIG: ConfigRef<Config> = ConfigRef::new(|| Config::load(DEFAULT_CONFIG_PATH));
+
+/// Command line arguments, they override the values of the env file
+#[derive(Parser, Debug)]
+#[command(about = "Authentication client")]
+pub struct Args {
+    /// Env file with the client settings [default: ./.env]
+    #[arg(long, value_name = "PATH")]
+    pub config: Option<String>,
+
+    /// Address of the server, IPv4 or IPv6 with port (e.g. [::1]:8080) or host name with port
+    #[arg(long, value_name = "ADDR")]
+    pub server: Option<String>,
+
+    /// Unix socket of a local server, used instead of the TCP address
+    #[arg(long, value_name = "PATH")]
+    pub unix: Option<String>,
+}
+
+/// Client settings read from the .env file at the root of the client folder.
+/// Missing keys keep their default value, see README for the list of keys.
+#[derive(Clone, Debug)]
+pub struct Config {
+    // Network
+    pub server_addr: String,
+    pub server_unix_socket: Option<String>,
+    pub reconnect_attempts: u32,
+    pub reconnect_delay: Duration,
+
+    // Transport security
+    pub tls_ca_cert: Option<String>,
+    pub tls_pinned_cert: Option<String>,
+    pub tls_server_name: String,
+    pub insecure_plaintext: bool,
+    pub max_frame_size: u32,
+
+    // YubiKey challenges are only signed for this server, `TLS_SERVER_NAME` by default
+    pub server_id: String,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            server_addr: String::from("127.0.0.1:8080"),
+            server_unix_socket: None,
+            reconnect_attempts: 5,
+            reconnect_delay: Duration::from_secs(1),
+            tls_ca_cert: None,
+            tls_pinned_cert: None,
+            tls_server_name: String::from("localhost"),
+            insecure_plaintext: false,
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            server_id: String::from("localhost"),
+        }
+    }
+}
+
+impl Config {
+    /// Load the settings for the given command line, must be called before any use of `CONFIG`
+    pub fn init(args: Args) -> Result<(), Box<dyn Error>> {
+        let mut config = match &args.config {
+            Some(path) if !Path::new(path).exists() => return Err(format!("No config file {}", path).into()),
+            Some(path) => Config::load(path),
+            None => Config::load(DEFAULT_CONFIG_PATH),
+        };
+
+        if let Some(server_addr) = args.server {
+            config.server_addr = server_addr;
+        }
+        if args.unix.is_some() {
+            config.server_unix_socket = args.unix;
+        }
+
+        Ok(CONFIG.set(config)?)
+    }
+
+    pub fn load(path: &str) -> Config {
+        let mut config = Config::default();
+
+        // Without a file we keep the defaults, the missing values are reported when used
+        let envfile = match EnvFile::new(Path::new(path)) {
+            Ok(envfile) => envfile,
+            Err(_) => return config,
+        };
+
+        let mut server_id = None;
+        for (key, value) in envfile.store {
+            match &*key {
+                "SERVER_ADDR" => config.server_addr = value,
+                "SERVER_UNIX_SOCKET" => config.server_unix_socket = Some(value),
+                "RECONNECT_ATTEMPTS" => config.reconnect_attempts = parse_number(&key, &value, config.reconnect_attempts),
+                "RECONNECT_DELAY" => config.reconnect_delay =
+                    Duration::from_secs(parse_number(&key, &value, config.reconnect_delay.as_secs())),
+                "TLS_CA_CERT" => config.tls_ca_cert = Some(value),
+                "TLS_PINNED_CERT" => config.tls_pinned_cert = Some(value),
+                "TLS_SERVER_NAME" => config.tls_server_name = value,
+                "INSECURE_PLAINTEXT" => config.insecure_plaintext = parse_bool(&value),
+                "MAX_FRAME_SIZE" => config.max_frame_size = parse_number(&key, &value, config.max_frame_size),
+                "SERVER_ID" => server_id = Some(value),
+                _ => {}
+            }
+        }
+        config.server_id = server_id.unwrap_or_else(|| config.tls_server_name.clone());
+
+        config
+    }
+}
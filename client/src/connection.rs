@@ -1,30 +1,110 @@
-use std::net::{TcpStream};
-use serde::Serialize;
-use serde::de::DeserializeOwned;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::thread;
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use rustls::{ClientConnection, StreamOwned};
 use std::error::Error;
+use app_tools::communication::data::{HelloData, Message, UnexpectedMessage};
+use app_tools::communication::framing::{read_frame, write_frame, FrameError};
+use app_tools::communication::messages::INCOMPATIBLE_VERSION;
+use crate::config::CONFIG;
+use crate::tls::{client_config, server_name};
 
-pub struct Connection {
-    stream: TcpStream
+/// Any byte stream the protocol can run over (TCP, TLS, Unix socket)
+pub trait Stream: Read + Write {}
+
+impl<T: Read + Write> Stream for T {}
+
+pub struct Connection<S: Stream = Box<dyn Stream>> {
+    stream: S,
+    max_frame_size: u32,
 }
 
-impl Connection {
-    pub fn new(addr: &str) -> Connection {
-        let stream = TcpStream::connect(addr);
-        let stream = match stream {
-            Err(e) => panic!("Connection ended up with error: {}", e),
-            Ok(s) => s
-        };
+/// Connect to the configured server, on the Unix socket if one is set and over TCP otherwise.
+/// A failed attempt is retried after a delay, doubled after each failure, up to `RECONNECT_ATTEMPTS` attempts.
+pub fn connect() -> Result<Connection, Box<dyn Error>> {
+    let mut delay = CONFIG.reconnect_delay;
+    let mut attempt = 1;
+    loop {
+        match open().and_then(Connection::new) {
+            Ok(connection) => return Ok(connection),
+            Err(e) if attempt >= CONFIG.reconnect_attempts => return Err(e),
+            Err(e) => println!("Connection failed (attempt {}/{}): {}", attempt, CONFIG.reconnect_attempts, e),
+        }
+
+        thread::sleep(delay);
+        delay *= 2;
+        attempt += 1;
+    }
+}
+
+fn open() -> Result<Box<dyn Stream>, Box<dyn Error>> {
+    #[cfg(unix)]
+    if let Some(path) = &CONFIG.server_unix_socket {
+        return Ok(Box::new(UnixStream::connect(path)?));
+    }
+
+    let stream = TcpStream::connect(&CONFIG.server_addr)?;
+    secure(stream).map_err(|e| format!("Secure connection ended up with error: {}", e).into())
+}
+
+/// Only the errors of the transport mean that the server can't be reached anymore,
+/// other errors leave the connection usable
+pub fn is_connection_lost(error: &(dyn Error + 'static)) -> bool {
+    matches!(error.downcast_ref::<FrameError>(), Some(FrameError::Io(_)))
+}
+
+/// Encrypt the TCP stream unless plaintext has been explicitly enabled
+fn secure(stream: TcpStream) -> Result<Box<dyn Stream>, Box<dyn Error>> {
+    match client_config()? {
+        Some(tls) => {
+            let mut tls_stream = StreamOwned::new(ClientConnection::new(tls, server_name()?)?, stream);
+            // Complete the handshake now so a bad certificate fails here and not on first send
+            while tls_stream.conn.is_handshaking() {
+                tls_stream.conn.complete_io(&mut tls_stream.sock)?;
+            }
+            Ok(Box::new(tls_stream))
+        },
+        None => Ok(Box::new(stream)),
+    }
+}
+
+impl<S: Stream> Connection<S> {
+    pub fn new(stream: S) -> Result<Connection<S>, Box<dyn Error>> {
+        let mut connection = Connection{stream, max_frame_size: CONFIG.max_frame_size};
+        connection.handshake().map_err(|e| format!("Handshake ended up with error: {}", e))?;
 
         println!("Connection to server is UP.\n");
 
-        Connection{stream}
+        Ok(connection)
+    }
+
+    fn handshake(&mut self) -> Result<(), Box<dyn Error>> {
+        self.send(HelloData::new())?;
+        let hello: HelloData = self.receive()?;
+        if !hello.is_compatible() {
+            return Err(format!("{}: server v{}, client v{}",
+                               INCOMPATIBLE_VERSION, hello.version, HelloData::new().version).into());
+        }
+        Ok(())
     }
 
-    pub fn send<T>(&mut self, o: &T) -> Result<(), Box<dyn Error>> where T: Serialize {
-        Ok(bincode::serialize_into(&self.stream, &o)?)
+    pub fn send<T>(&mut self, o: T) -> Result<(), Box<dyn Error>> where T: Into<Message> {
+        Ok(write_frame(&mut self.stream, &o.into(), self.max_frame_size)?)
     }
 
-    pub fn receive<T>(&mut self) -> Result<T, Box<dyn Error>> where T: DeserializeOwned {
-        Ok(bincode::deserialize_from(&self.stream)?)
+    pub fn receive<T>(&mut self) -> Result<T, Box<dyn Error>> where T: TryFrom<Message, Error = UnexpectedMessage> {
+        let message: Message = read_frame(&mut self.stream, self.max_frame_size)?;
+        Ok(T::try_from(message)?)
     }
-}
\ No newline at end of file
+}
+
+impl Connection {
+    /// Replace the lost connection by a new one, with the same attempts as `connect`
+    pub fn reconnect(&mut self) -> Result<(), Box<dyn Error>> {
+        println!("Reconnecting to server...");
+        *self = connect()?;
+        Ok(())
+    }
+}
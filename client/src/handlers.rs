@@ -1,12 +1,19 @@
 use read_input::prelude::*;
+use app_tools::communication::data::{SecondFactorType, VerificationPurpose};
 use app_tools::communication::messages::{INVALID_EMAIL,
+                                         INVALID_LABEL,
                                          INVALID_PASSWORD,
                                          INVALID_PIN,
+                                         INVALID_RECOVERY_CODE,
+                                         INVALID_TOTP_CODE,
                                          INVALID_UUID};
 use app_tools::input_validation::{email::validate_email,
+                       key_label::validate_key_label,
                        password::validate_password,
                        uuid::validate_uuid,
-                       pin::validate_pin};
+                       pin::validate_pin,
+                       recovery_code::validate_recovery_code,
+                       totp_code::validate_totp_code};
 
 pub fn ask_email() -> String {
     loop {
@@ -48,3 +55,59 @@ pub fn ask_pin() -> String {
     }
 }
 
+pub fn ask_key_label() -> String {
+    loop {
+        let label_input = input::<String>().msg("- Yubikey label: ").get();
+        if validate_key_label(&label_input) {
+            return label_input;
+        }
+        println!("{}", INVALID_LABEL);
+    }
+}
+
+pub fn ask_totp_code() -> String {
+    loop {
+        let code_input = input::<String>().msg("- Authenticator code: ").get();
+        if validate_totp_code(&code_input) {
+            return code_input;
+        }
+        println!("{}", INVALID_TOTP_CODE);
+    }
+}
+
+pub fn ask_recovery_code() -> String {
+    loop {
+        let code_input = input::<String>().msg("- Recovery code: ").get();
+        if validate_recovery_code(&code_input) {
+            return code_input;
+        }
+        println!("{}", INVALID_RECOVERY_CODE);
+    }
+}
+
+/// The user only chooses if several factors are enrolled
+pub fn ask_second_factor(factors: &[SecondFactorType]) -> SecondFactorType {
+    if factors.len() == 1 {
+        return factors[0];
+    }
+    for (i, factor) in factors.iter().enumerate() {
+        println!("{}.\t{:?}", i + 1, factor);
+    }
+    let choice = input::<usize>()
+        .msg("- Second factor: ")
+        .inside(1..=factors.len())
+        .get();
+    factors[choice - 1]
+}
+
+pub fn ask_verification_purpose() -> VerificationPurpose {
+    let purposes = [VerificationPurpose::Registration, VerificationPurpose::PasswordReset];
+    for (i, purpose) in purposes.iter().enumerate() {
+        println!("{}.\t{:?}", i + 1, purpose);
+    }
+    let choice = input::<usize>()
+        .msg("- Email token of: ")
+        .inside(1..=purposes.len())
+        .get();
+    purposes[choice - 1]
+}
@@ -4,43 +4,85 @@ mod action;
 mod yubi;
 mod handlers;
 mod authentication_tools;
+mod config;
+mod tls;
 
+use std::process;
+use clap::Parser;
 use read_input::prelude::*;
 use crate::authentication::Authenticate;
-use crate::connection::Connection;
+use crate::config::{Args, Config};
+use crate::connection::{connect, is_connection_lost, Connection};
 use crate::action::Action;
 
-const SERVER_IP: &str = "127.0.0.1:8080";
-
 fn main() {
+    if let Err(e) = Config::init(Args::parse()) {
+        eprintln!("Invalid configuration: {}", e);
+        process::exit(1);
+    }
+
     // Setup
     println!("--- Client ---");
-    let mut connection = Connection::new(SERVER_IP);
+    let mut connection = match connect() {
+        Ok(connection) => connection,
+        Err(e) => {
+            eprintln!("Server unreachable: {}", e);
+            process::exit(1);
+        }
+    };
 
     loop {
         // Authentication
-        loop {
+        let mut session = loop {
             Authenticate::display();
             let action = input::<Authenticate>().msg("Please select: ").get();
 
             match action.perform(&mut connection) {
-                Ok(_) => break,
-                Err(e) => eprintln!("Authentication failed with following errors: {}\n", e)
+                Ok(session) => break session,
+                Err(e) => {
+                    eprintln!("Authentication failed with following errors: {}\n", e);
+                    if is_connection_lost(&*e) {
+                        reconnect(&mut connection);
+                    }
+                }
             };
         };
 
         println!("\n[[ Authentication success ]]\n");
+        println!("{} recovery codes left\n", session.recovery_codes);
 
         loop {
             Action::display();
             let action = input::<Action>().msg("Please select: ").get();
 
-            match action.perform(&mut connection) {
+            match action.perform(&mut connection, &session.token) {
                 Ok(end) => if !end { break },
+                Err(e) if is_connection_lost(&*e) => {
+                    eprintln!("Connection lost: {}\n", e);
+                    reconnect(&mut connection);
+                    match Authenticate::resume(&mut connection, &session.ticket) {
+                        Ok(resumed) => {
+                            session = resumed;
+                            println!("\n[[ Session resumed ]]\n");
+                        },
+                        Err(e) => {
+                            eprintln!("Session could not be resumed: {}\n", e);
+                            break
+                        }
+                    }
+                },
                 Err(e) => eprintln!("Operation failed with following errors: {}\n", e)
             };
         }
 
         println!("\n[[ Logged Out ]]\n");
     }
-}
\ No newline at end of file
+}
+
+/// Without the server there is nothing left to do
+fn reconnect(connection: &mut Connection) {
+    if let Err(e) = connection.reconnect() {
+        eprintln!("Server unreachable: {}", e);
+        process::exit(1);
+    }
+}
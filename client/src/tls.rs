@@ -0,0 +1,80 @@
+use std::error::Error;
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::Arc;
+use std::time::SystemTime;
+use rustls::{Certificate, ClientConfig, RootCertStore, ServerName};
+use rustls::client::{ServerCertVerified, ServerCertVerifier};
+
+use app_tools::security::crypto::hash_sha256;
+use crate::config::CONFIG;
+
+/// Build the TLS configuration, the server is either checked against a pinned
+/// certificate (SHA-256 fingerprint) or against a CA certificate.
+/// # Returns
+/// * `Option<Arc<ClientConfig>>` - `None` only if plaintext has been explicitly enabled
+/// # Errors
+/// * `String` - No way to verify the server is configured or the CA can't be loaded
+pub fn client_config() -> Result<Option<Arc<ClientConfig>>, Box<dyn Error>> {
+    if CONFIG.insecure_plaintext {
+        println!("WARNING: TLS is disabled, everything is sent in clear (INSECURE_PLAINTEXT)");
+        return Ok(None);
+    }
+
+    let builder = ClientConfig::builder().with_safe_defaults();
+
+    let config = if let Some(fingerprint) = &CONFIG.tls_pinned_cert {
+        builder.with_custom_certificate_verifier(Arc::new(PinnedCertVerifier::new(fingerprint)))
+            .with_no_client_auth()
+    } else if let Some(ca_path) = &CONFIG.tls_ca_cert {
+        let mut reader = BufReader::new(File::open(ca_path)
+            .map_err(|e| format!("Could not read CA certificate {}: {}", ca_path, e))?);
+        let mut roots = RootCertStore::empty();
+        for cert in rustls_pemfile::certs(&mut reader)? {
+            roots.add(&Certificate(cert))?;
+        }
+        builder.with_root_certificates(roots).with_no_client_auth()
+    } else {
+        return Err("TLS_CA_CERT or TLS_PINNED_CERT must be set (or INSECURE_PLAINTEXT for local development)".into());
+    };
+
+    Ok(Some(Arc::new(config)))
+}
+
+pub fn server_name() -> Result<ServerName, Box<dyn Error>> {
+    Ok(ServerName::try_from(CONFIG.tls_server_name.as_str())?)
+}
+
+/// Accept only the server certificate whose SHA-256 matches the pinned one,
+/// the handshake signatures are still verified with this certificate.
+struct PinnedCertVerifier {
+    fingerprint: String,
+}
+
+impl PinnedCertVerifier {
+    fn new(fingerprint: &str) -> PinnedCertVerifier {
+        // Accept "AB:CD:..." as well as "abcd..."
+        PinnedCertVerifier { fingerprint: fingerprint.replace(':', "").to_lowercase() }
+    }
+}
+
+impl ServerCertVerifier for PinnedCertVerifier {
+    fn verify_server_cert(&self,
+                          end_entity: &Certificate,
+                          _intermediates: &[Certificate],
+                          _server_name: &ServerName,
+                          _scts: &mut dyn Iterator<Item = &[u8]>,
+                          _ocsp_response: &[u8],
+                          _now: SystemTime) -> Result<ServerCertVerified, rustls::Error> {
+        let fingerprint: String = hash_sha256(&end_entity.0)
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+
+        if fingerprint == self.fingerprint {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::General(String::from("Server certificate doesn't match the pinned one")))
+        }
+    }
+}
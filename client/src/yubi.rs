@@ -1,6 +1,7 @@
 use std::io;
 use std::io::Read;
-use yubikey::{Context, YubiKey, piv, PinPolicy, TouchPolicy, MgmKey, Buffer};
+use yubikey::{Context, YubiKey, piv, PinPolicy, TouchPolicy, MgmKey, Buffer, Certificate};
+use app_tools::communication::data::AttestationData;
 use x509::SubjectPublicKeyInfo;
 use crate::handlers::ask_pin;
 
@@ -17,22 +18,44 @@ impl Yubi {
                 }
             }
 
-            println!("No Yubikey detected: Please enter one and press [Enter] to continue...");
-            let _ = io::stdin().read(&mut [0u8]).unwrap();
+            Yubi::wait_for("No Yubikey detected: Please enter one");
         }
     }
 
-    pub fn generate_keys() -> YubiKeyResult<Vec<u8>> {
+    /// Let the user plug in or swap a key
+    pub fn wait_for(message: &str) {
+        println!("{} and press [Enter] to continue...", message);
+        let _ = io::stdin().read(&mut [0u8]).unwrap();
+    }
+
+    /// Generate the authentication key, with the attestation proving it was generated on the YubiKey
+    /// if the firmware supports it (4.3 and later)
+    pub fn generate_keys() -> YubiKeyResult<(Vec<u8>, Option<AttestationData>)> {
         let mut yubikey = Yubi::auto_yk()?;
         // I use default management key because implement an other is much harder
         // and it wasn't necessary for this testing lab.
         yubikey.authenticate(MgmKey::default())?;
-        Ok(piv::generate(&mut yubikey,
-                         piv::SlotId::Authentication,
-                     piv::AlgorithmId::EccP256,
-                     PinPolicy::Always,
-                 TouchPolicy::Never)
-            ?.public_key())
+        let public_key = piv::generate(&mut yubikey,
+                                       piv::SlotId::Authentication,
+                                       piv::AlgorithmId::EccP256,
+                                       PinPolicy::Always,
+                                       TouchPolicy::Never)?.public_key();
+
+        let attestation = Yubi::attest(&mut yubikey);
+        if attestation.is_none() {
+            println!("This yubikey can't attest its keys, the server may refuse it");
+        }
+        Ok((public_key, attestation))
+    }
+
+    /// Certificate of the slot signed by the attestation key of the YubiKey, and the certificate of this key
+    fn attest(yubikey: &mut YubiKey) -> Option<AttestationData> {
+        let certificate = piv::attest(yubikey, piv::SlotId::Authentication).ok()?;
+        let intermediate = Certificate::read(yubikey, piv::SlotId::Attestation).ok()?;
+        Some(AttestationData {
+            certificate: certificate.to_vec(),
+            intermediate: intermediate.as_ref().to_vec(),
+        })
     }
 
     pub fn sign(bytes: &[u8]) -> YubiKeyResult<Buffer> {
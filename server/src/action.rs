@@ -1,36 +1,234 @@
-use serde::{Serialize, Deserialize};
 use std::error::Error;
-use app_tools::communication::data::ChangeTwoFA;
+use app_tools::communication::data::{ActionData,
+                                     ActionRequest,
+                                     AddYubiKeyData,
+                                     ChallengeData,
+                                     ChangeTwoFA,
+                                     RecoveryCodesData,
+                                     SecondFactorType,
+                                     ServerResponse,
+                                     TotpCodeData,
+                                     TotpSecretData,
+                                     YubiKeyLabelData,
+                                     YubiKeysData};
+use app_tools::communication::status::StatusCode;
+use app_tools::input_validation::key_label::validate_key_label;
+use app_tools::security::challenge::{Challenge, ChallengePurpose};
+use app_tools::security::crypto::SecretBytes;
+use app_tools::security::{recovery, totp};
+use crate::config::CONFIG;
 use crate::connection::Connection;
-use crate::authentication::User;
+use crate::authentication::{TotpFactor, User, YubiKeyFactor};
+use crate::authentication_tools::{attest_yubikey, now, validate_public_key, verify_challenge_yubikey, verify_totp};
 use crate::database::Database;
+use crate::session::{self, AuthLevel, Session};
 
-/// `Action` enum is used to perform logged operations:
+/// `Action` is used to perform logged operations:
 /// -   Enable/Disable 2fa authentication
-#[derive(Serialize, Deserialize, Debug)]
-pub enum Action {
-    Switch2FA,
-    Logout
-}
+/// -   Enrol an authenticator app (TOTP)
+/// -   Generate new recovery codes
+/// -   List, add and remove YubiKeys
+pub struct Action;
 
 impl Action {
-    pub fn perform(user: &mut User, connection: &mut Connection) -> Result<bool, Box<dyn Error>> {
-        match connection.receive()? {
-            Action::Switch2FA => Action::switch_2fa(user, connection),
-            Action::Logout => Ok(false)
+    pub async fn perform(user: &mut User,
+                         session: &Session,
+                         connection: &mut Connection) -> Result<bool, Box<dyn Error + Send + Sync>> {
+        // A session parked without any action is logged out
+        let action: ActionData = match connection.receive_request().await {
+            Ok(action) => action,
+            Err(error) => return Err(format!("Session of {} ended: {}", user.email, error).into()),
+        };
+
+        // The token must be the one of this session, still valid and not revoked
+        let valid = session::verify(&action.token).is_some_and(|token_session| token_session.id == session.id);
+        if action.request == ActionRequest::Logout {
+            return Ok(false);
+        }
+
+        // The account may have changed on another connection since the login (e.g. a recovery code used,
+        // 2FA enabled), so the action works on its saved state
+        if valid {
+            match Database::get(&user.email)? {
+                Some(saved_user) => *user = saved_user,
+                None => return Err(format!("Account {} not found", user.email).into()),
+            }
+        }
+
+        let status = if !valid {
+            StatusCode::SessionExpired
+        } else if user.two_fa && session.auth_level != AuthLevel::TwoFactor && Action::needs_second_factor(action.request) {
+            StatusCode::SecondFactorRequired
+        } else {
+            StatusCode::Accepted
+        };
+        connection.send(ServerResponse {
+            status,
+        }).await?;
+        match status {
+            StatusCode::SessionExpired => {
+                println!("Session of {} expired or revoked", user.email);
+                return Ok(false);
+            },
+            StatusCode::SecondFactorRequired => {
+                println!("Session of {} opened without second factor, {:?} refused", user.email, action.request);
+                return Ok(true);
+            },
+            _ => {},
+        }
+
+        match action.request {
+            ActionRequest::Switch2FA => Action::switch_2fa(user, connection).await,
+            ActionRequest::EnrolTotp => Action::enrol_totp(user, connection).await,
+            ActionRequest::GenerateRecoveryCodes => Action::generate_recovery_codes(user, connection).await,
+            ActionRequest::ListYubiKeys => Action::list_yubikeys(user, connection).await,
+            ActionRequest::AddYubiKey => Action::add_yubikey(user, session, connection).await,
+            ActionRequest::RemoveYubiKey => Action::remove_yubikey(user, connection).await,
+            ActionRequest::Logout => Ok(false)
         }
     }
 
-    fn switch_2fa(user: &mut User, connection: &mut Connection) -> Result<bool, Box<dyn Error>> {
+    /// Actions that disable or replace a second factor. Once 2FA is enabled, they need a session
+    /// opened with the second factor, e.g. not one opened with the password only before 2FA was enabled.
+    fn needs_second_factor(request: ActionRequest) -> bool {
+        matches!(request, ActionRequest::Switch2FA
+            | ActionRequest::EnrolTotp
+            | ActionRequest::GenerateRecoveryCodes
+            | ActionRequest::RemoveYubiKey)
+    }
+
+    async fn switch_2fa(user: &mut User, connection: &mut Connection) -> Result<bool, Box<dyn Error + Send + Sync>> {
         // Update 2 FA status in BD
         user.two_fa = !user.two_fa;
-        Database::insert(&user)?;
+        let two_fa = user.two_fa;
+        Database::update(&user.email, move |user| user.two_fa = two_fa).await?;
 
         // Send new 2 FA status to client
-        connection.send(&ChangeTwoFA {
+        connection.send(ChangeTwoFA {
             two_fa_status: user.two_fa
-        })?;
+        }).await?;
+
+        Ok(true)
+    }
+
+    /// The new secret replaces the previous one once the user has confirmed it with a first code
+    async fn enrol_totp(user: &mut User, connection: &mut Connection) -> Result<bool, Box<dyn Error + Send + Sync>> {
+        let secret = totp::generate_secret();
+        connection.send(TotpSecretData {
+            uri: totp::otpauth_uri(secret.as_bytes(), &CONFIG.totp_issuer, &user.email),
+        }).await?;
+
+        let code_data: TotpCodeData = connection.receive().await?;
+        let status = match verify_totp(secret.as_bytes(), &code_data.code, None) {
+            Some(step) => {
+                Database::update(&user.email, move |user| {
+                    user.totp = Some(TotpFactor {
+                        secret,
+                        last_step: step,
+                    });
+                    if !user.second_factors.contains(&SecondFactorType::Totp) {
+                        user.second_factors.push(SecondFactorType::Totp);
+                    }
+                }).await?;
+                StatusCode::TotpEnrolled
+            },
+            None => StatusCode::WrongCode,
+        };
+
+        connection.send(ServerResponse {
+            status,
+        }).await?;
+        Ok(true)
+    }
+
+    /// The new codes replace all the previous ones, used or not
+    async fn generate_recovery_codes(user: &mut User, connection: &mut Connection) -> Result<bool, Box<dyn Error + Send + Sync>> {
+        let codes = recovery::generate_codes();
+        let hashes: Vec<SecretBytes> = codes.iter().map(|code| recovery::hash_code(code)).collect();
+        Database::update(&user.email, |user| user.recovery_codes = hashes).await?;
+
+        connection.send(RecoveryCodesData {
+            codes,
+        }).await?;
+        Ok(true)
+    }
+
+    async fn list_yubikeys(user: &mut User, connection: &mut Connection) -> Result<bool, Box<dyn Error + Send + Sync>> {
+        connection.send(YubiKeysData {
+            keys: user.yubikeys.iter().map(YubiKeyFactor::info).collect(),
+        }).await?;
+        Ok(true)
+    }
+
+    /// One of the registered keys must sign a challenge,
+    /// so a stolen session can't add a key of its own
+    async fn add_yubikey(user: &mut User, session: &Session, connection: &mut Connection) -> Result<bool, Box<dyn Error + Send + Sync>> {
+        let challenge = Challenge::new(ChallengePurpose::AddYubiKey, &CONFIG.server_id, session.id, now());
+        connection.send(ChallengeData {
+            challenge: challenge.clone(),
+        }).await?;
+
+        let add_data: AddYubiKeyData = connection.receive().await?;
+        let signer = verify_challenge_yubikey(&mut user.yubikeys,
+                                              &challenge,
+                                              ChallengePurpose::AddYubiKey,
+                                              &session.id,
+                                              &add_data.signature);
+        let status = match signer {
+            None => StatusCode::WrongKey,
+            Some(_) if !validate_public_key(&add_data.public_key) => StatusCode::InvalidPublicKey,
+            Some(_) if !validate_key_label(&add_data.label) => StatusCode::InvalidLabel,
+            Some(index) => match attest_yubikey(&add_data.public_key, &add_data.attestation) {
+                Ok(attestation) => {
+                    let yubikey = YubiKeyFactor::new(add_data.label, add_data.public_key, attestation);
+                    let signer = user.yubikeys[index].clone();
+                    // Checked on the saved keys, another connection may have added the same key
+                    Database::update(&user.email, move |user| {
+                        if user.yubikeys.iter().any(|saved| saved.public_key == yubikey.public_key) {
+                            return StatusCode::InvalidPublicKey;
+                        }
+                        if user.yubikeys.iter().any(|saved| saved.label == yubikey.label) {
+                            return StatusCode::InvalidLabel;
+                        }
+                        if let Some(saved) = user.yubikeys.iter_mut().find(|saved| saved.public_key == signer.public_key) {
+                            saved.last_used = signer.last_used;
+                        }
+                        user.yubikeys.push(yubikey);
+                        StatusCode::YubiKeyAdded
+                    }).await?.unwrap_or(StatusCode::WrongKey)
+                },
+                Err(status) => status,
+            },
+        };
+
+        connection.send(ServerResponse {
+            status,
+        }).await?;
+        Ok(true)
+    }
+
+    /// The last key can't be removed, it is needed to reset the password
+    async fn remove_yubikey(user: &mut User, connection: &mut Connection) -> Result<bool, Box<dyn Error + Send + Sync>> {
+        connection.send(YubiKeysData {
+            keys: user.yubikeys.iter().map(YubiKeyFactor::info).collect(),
+        }).await?;
+
+        // Removed from the saved keys, so two connections can't remove the last two keys
+        let label_data: YubiKeyLabelData = connection.receive().await?;
+        let status = Database::update(&user.email, move |user| {
+            match user.yubikeys.iter().position(|yubikey| yubikey.label == label_data.label) {
+                None => StatusCode::UnknownYubiKey,
+                Some(_) if user.yubikeys.len() == 1 => StatusCode::LastYubiKey,
+                Some(index) => {
+                    user.yubikeys.remove(index);
+                    StatusCode::YubiKeyRemoved
+                },
+            }
+        }).await?.unwrap_or(StatusCode::UnknownYubiKey);
 
+        connection.send(ServerResponse {
+            status,
+        }).await?;
         Ok(true)
     }
 }
\ No newline at end of file
@@ -0,0 +1,271 @@
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::OnceLock;
+use p256::ecdsa::VerifyingKey;
+use x509_parser::certificate::X509Certificate;
+use x509_parser::der_parser::der::parse_der_integer;
+use x509_parser::prelude::FromDer;
+use app_tools::communication::data::AttestationData;
+use crate::config::CONFIG;
+
+// Extensions of the slot certificates, see https://developers.yubico.com/PIV/Introduction/PIV_attestation.html
+const FIRMWARE_OID: &str = "1.3.6.1.4.1.41482.3.3";
+const SERIAL_OID: &str = "1.3.6.1.4.1.41482.3.7";
+const POLICY_OID: &str = "1.3.6.1.4.1.41482.3.8";
+
+/// DER of the Yubico certificates trusted to sign the attestation keys of the YubiKeys
+static ROOTS: OnceLock<Vec<Vec<u8>>> = OnceLock::new();
+
+/// What the YubiKey certifies about a key generated on it
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct KeyAttestation {
+    pub serial: u32,
+    pub firmware: String,
+    pub pin_policy: PinPolicy,
+    pub touch_policy: TouchPolicy,
+}
+
+/// When the PIN must be entered before the key signs
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum PinPolicy {
+    Never,
+    Once,
+    Always,
+}
+
+/// When the key must be touched before it signs, `Cached` is once every 15 seconds
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum TouchPolicy {
+    Never,
+    Always,
+    Cached,
+}
+
+/// Load the Yubico root and intermediate certificates from `ATTESTATION_ROOTS`, every intermediate
+/// must be signed by another certificate of the file. Without file the attestations aren't checked.
+/// # Returns
+/// * `usize` - The number of certificates loaded
+/// # Errors
+/// * `String` - The file can't be read, or `REQUIRE_ATTESTATION` is set without it
+pub fn load() -> Result<usize, Box<dyn Error + Send + Sync>> {
+    let roots = match &CONFIG.attestation_roots {
+        Some(path) => load_certs(path)?,
+        None if CONFIG.require_attestation => return Err("REQUIRE_ATTESTATION needs ATTESTATION_ROOTS".into()),
+        None => vec![],
+    };
+
+    let count = roots.len();
+    ROOTS.set(roots).map_err(|_| "Attestation roots already loaded")?;
+    Ok(count)
+}
+
+/// Whether the keys can be attested, the others are registered without attestation
+pub fn enabled() -> bool {
+    !roots().is_empty()
+}
+
+/// Check that the key has been generated on a YubiKey: the slot certificate must certify
+/// the public key and be signed by the attestation key, itself certified by a configured certificate
+/// # Errors
+/// * `String` - A certificate is malformed, expired or not signed by the expected key
+pub fn verify(public_key: &[u8], attestation: &AttestationData) -> Result<KeyAttestation, String> {
+    verify_with(roots(), public_key, attestation)
+}
+
+fn verify_with(roots: &[Vec<u8>], public_key: &[u8], attestation: &AttestationData) -> Result<KeyAttestation, String> {
+    let certificate = parse(&attestation.certificate)?;
+    let intermediate = parse(&attestation.intermediate)?;
+
+    if certificate.issuer() != intermediate.subject()
+        || certificate.verify_signature(Some(intermediate.public_key())).is_err() {
+        return Err("The slot certificate isn't signed by the attestation key".into());
+    }
+    let roots = roots.iter().map(|root| parse(root)).collect::<Result<Vec<_>, _>>()?;
+    if !roots.iter().any(|root| is_issuer(root, &intermediate)) {
+        return Err(format!("The attestation certificate of {} isn't signed by Yubico", intermediate.subject()));
+    }
+    if !certificate.validity().is_valid() || !intermediate.validity().is_valid() {
+        return Err("The attestation has expired".into());
+    }
+
+    let attested = VerifyingKey::from_sec1_bytes(&certificate.public_key().subject_public_key.data)
+        .map_err(|_| "The slot certificate doesn't certify a P-256 key")?;
+    let submitted = VerifyingKey::from_sec1_bytes(public_key).map_err(|_| "Invalid public key")?;
+    if attested != submitted {
+        return Err("The slot certificate certifies another key".into());
+    }
+
+    let extension = |oid: &str| certificate.extensions().iter()
+        .find(|extension| extension.oid.to_id_string() == oid)
+        .map(|extension| extension.value)
+        .ok_or(format!("The slot certificate has no extension {}", oid));
+
+    let (pin_policy, touch_policy) = parse_policy(extension(POLICY_OID)?)?;
+    Ok(KeyAttestation {
+        serial: parse_serial(extension(SERIAL_OID)?)?,
+        firmware: parse_firmware(extension(FIRMWARE_OID)?)?,
+        pin_policy,
+        touch_policy,
+    })
+}
+
+fn parse_serial(value: &[u8]) -> Result<u32, String> {
+    parse_der_integer(value)
+        .ok()
+        .and_then(|(_, serial)| serial.as_u32().ok())
+        .ok_or_else(|| "Malformed serial number".into())
+}
+
+fn parse_firmware(value: &[u8]) -> Result<String, String> {
+    match value {
+        [major, minor, patch] => Ok(format!("{}.{}.{}", major, minor, patch)),
+        _ => Err("Malformed firmware version".into()),
+    }
+}
+
+fn parse_policy(value: &[u8]) -> Result<(PinPolicy, TouchPolicy), String> {
+    match value {
+        [pin, touch] => Ok((PinPolicy::from_byte(*pin).ok_or("Unknown PIN policy")?,
+                            TouchPolicy::from_byte(*touch).ok_or("Unknown touch policy")?)),
+        _ => Err("Malformed PIN and touch policy".into()),
+    }
+}
+
+impl PinPolicy {
+    fn from_byte(value: u8) -> Option<PinPolicy> {
+        match value {
+            1 => Some(PinPolicy::Never),
+            2 => Some(PinPolicy::Once),
+            3 => Some(PinPolicy::Always),
+            _ => None,
+        }
+    }
+}
+
+impl TouchPolicy {
+    fn from_byte(value: u8) -> Option<TouchPolicy> {
+        match value {
+            1 => Some(TouchPolicy::Never),
+            2 => Some(TouchPolicy::Always),
+            3 => Some(TouchPolicy::Cached),
+            _ => None,
+        }
+    }
+}
+
+fn roots() -> &'static [Vec<u8>] {
+    ROOTS.get().map_or(&[], Vec::as_slice)
+}
+
+fn parse(der: &[u8]) -> Result<X509Certificate<'_>, String> {
+    match X509Certificate::from_der(der) {
+        Ok(([], certificate)) => Ok(certificate),
+        _ => Err("Malformed certificate".into()),
+    }
+}
+
+fn is_issuer(issuer: &X509Certificate, certificate: &X509Certificate) -> bool {
+    certificate.issuer() == issuer.subject() && certificate.verify_signature(Some(issuer.public_key())).is_ok()
+}
+
+fn load_certs(path: &str) -> Result<Vec<Vec<u8>>, Box<dyn Error + Send + Sync>> {
+    let mut reader = BufReader::new(File::open(path)
+        .map_err(|e| format!("Could not read attestation roots {}: {}", path, e))?);
+    let certs = rustls_pemfile::certs(&mut reader)?;
+    if certs.is_empty() {
+        return Err(format!("No certificate found in {}", path).into());
+    }
+
+    let parsed = certs.iter().map(|cert| parse(cert)).collect::<Result<Vec<_>, _>>()?;
+    // The roots sign themselves
+    if let Some(cert) = parsed.iter().find(|cert| !parsed.iter().any(|issuer| is_issuer(issuer, cert))) {
+        return Err(format!("{} isn't signed by a certificate of {}", cert.subject(), path).into());
+    }
+    Ok(certs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Made by tests/attestation/generate.sh
+    const ROOT: &[u8] = include_bytes!("../tests/attestation/root.der");
+    const INTERMEDIATE: &[u8] = include_bytes!("../tests/attestation/intermediate.der");
+    const SLOT: &[u8] = include_bytes!("../tests/attestation/slot.der");
+    const SLOT_PUBLIC_KEY: &[u8] = include_bytes!("../tests/attestation/slot.pub");
+    const NO_EXTENSIONS: &[u8] = include_bytes!("../tests/attestation/no_extensions.der");
+    const ROGUE_INTERMEDIATE: &[u8] = include_bytes!("../tests/attestation/rogue_intermediate.der");
+    const ROGUE_SLOT: &[u8] = include_bytes!("../tests/attestation/rogue_slot.der");
+
+    fn attestation(certificate: &[u8], intermediate: &[u8]) -> AttestationData {
+        AttestationData {
+            certificate: certificate.to_vec(),
+            intermediate: intermediate.to_vec(),
+        }
+    }
+
+    #[test]
+    fn valid_attestation() {
+        let attested = verify_with(&[ROOT.to_vec()], SLOT_PUBLIC_KEY, &attestation(SLOT, INTERMEDIATE)).unwrap();
+
+        assert_eq!(attested.serial, 12345678);
+        assert_eq!(attested.firmware, "5.4.3");
+        assert_eq!(attested.pin_policy, PinPolicy::Always);
+        assert_eq!(attested.touch_policy, TouchPolicy::Never);
+    }
+
+    #[test]
+    fn chain_not_verified() {
+        let roots = [ROOT.to_vec()];
+
+        // The attestation certificate isn't signed by a root
+        assert!(verify_with(&roots, SLOT_PUBLIC_KEY, &attestation(ROGUE_SLOT, ROGUE_INTERMEDIATE)).is_err());
+        assert!(verify_with(&[], SLOT_PUBLIC_KEY, &attestation(SLOT, INTERMEDIATE)).is_err());
+        // The slot certificate isn't signed by the attestation key sent with it
+        assert!(verify_with(&roots, SLOT_PUBLIC_KEY, &attestation(ROGUE_SLOT, INTERMEDIATE)).is_err());
+        assert!(verify_with(&roots, SLOT_PUBLIC_KEY, &attestation(SLOT, ROGUE_INTERMEDIATE)).is_err());
+        assert!(verify_with(&roots, SLOT_PUBLIC_KEY, &attestation(SLOT, ROOT)).is_err());
+        // Certificates that can't be parsed
+        assert!(verify_with(&roots, SLOT_PUBLIC_KEY, &attestation(&SLOT[..SLOT.len() - 1], INTERMEDIATE)).is_err());
+        assert!(verify_with(&roots, SLOT_PUBLIC_KEY, &attestation(SLOT, &[])).is_err());
+    }
+
+    #[test]
+    fn other_public_key() {
+        let roots = [ROOT.to_vec()];
+        let other = p256::ecdsa::SigningKey::from_bytes(&[7; 32]).unwrap();
+        let other = other.verifying_key().to_encoded_point(false);
+
+        let error = verify_with(&roots, other.as_bytes(), &attestation(SLOT, INTERMEDIATE));
+        assert!(error.unwrap_err().contains("another key"));
+        assert!(verify_with(&roots, &SLOT_PUBLIC_KEY[..64], &attestation(SLOT, INTERMEDIATE)).is_err());
+        assert!(verify_with(&roots, &[], &attestation(SLOT, INTERMEDIATE)).is_err());
+    }
+
+    #[test]
+    fn missing_extensions() {
+        let error = verify_with(&[ROOT.to_vec()], SLOT_PUBLIC_KEY, &attestation(NO_EXTENSIONS, INTERMEDIATE));
+
+        assert!(error.unwrap_err().contains("has no extension"));
+    }
+
+    #[test]
+    fn malformed_extensions() {
+        // Pass
+        assert_eq!(parse_serial(&[0x02, 0x04, 0x00, 0xbc, 0x61, 0x4e]), Ok(12345678));
+        assert_eq!(parse_firmware(&[5, 7, 1]), Ok("5.7.1".to_string()));
+        assert_eq!(parse_policy(&[2, 3]), Ok((PinPolicy::Once, TouchPolicy::Cached)));
+
+        // Fail
+        assert!(parse_serial(&[]).is_err());
+        assert!(parse_serial(&[0x04, 0x01, 0x00]).is_err());
+        assert!(parse_serial(&[0x02, 0x05, 0x01, 0x00, 0x00, 0x00, 0x00]).is_err());
+        assert!(parse_firmware(&[5, 7]).is_err());
+        assert!(parse_firmware(&[5, 7, 1, 0]).is_err());
+        assert!(parse_policy(&[2]).is_err());
+        assert!(parse_policy(&[0, 1]).is_err());
+        assert!(parse_policy(&[1, 4]).is_err());
+    }
+}
\ No newline at end of file
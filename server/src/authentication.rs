@@ -1,300 +1,812 @@
 use serde::{Serialize, Deserialize};
 use std::error::Error;
+use tokio::time::timeout;
 
-use app_tools::security::crypto::{generate_random_16_bytes, hashmac_sha256};
+use app_tools::security::challenge::{Challenge, ChallengePurpose};
+use app_tools::security::crypto::{derive_password_params, derive_salt, Argon2Params, SecretBytes};
+use app_tools::security::{recovery, srp};
 use app_tools::communication::data::*;
-use app_tools::communication::messages::*;
-use app_tools::input_validation::{email::validate_email, password::validate_password};
+use app_tools::communication::status::StatusCode;
+use app_tools::input_validation::email::validate_email;
+use app_tools::input_validation::key_label::validate_key_label;
+use app_tools::input_validation::uuid::validate_uuid;
 
+use crate::config::CONFIG;
 use crate::connection::Connection;
 use crate::database::Database;
-use crate::authentication_tools::{hash_password,
+use crate::session::{self, AuthLevel, Session};
+use crate::lockout::{self, LoginFailures};
+use crate::mailer::send_mail;
+use crate::pepper;
+use crate::verification::PendingVerification;
+use crate::attestation::KeyAttestation;
+use crate::authentication_tools::{attest_yubikey,
+                                  dummy_public_key,
+                                  dummy_verifier,
+                                  now,
                                   send_token_email,
-                                  validate_email_uuid,
+                                  srp_server_public,
+                                  srp_verify_client,
                                   validate_public_key,
-                                  verify_challenge_yubikey};
+                                  verify_challenge_yubikey,
+                                  verify_totp};
 
-/// `Authenticate` enum is used to perform:
+/// Email of the dummy user of an unknown email, it isn't valid so no account can have it
+const UNKNOWN_EMAIL: &str = "unknown account";
+
+/// `Authenticate` is used to perform:
 /// -   Authentication
 /// -   Registration
 /// -   Password Reset
-#[derive(Serialize, Deserialize, PartialEq, Debug)]
-pub enum Authenticate {
-    Authenticate,
-    Register,
-    Reset,
-    Exit
-}
+/// -   Session resumption
+/// -   Email verification on a new connection
+pub struct Authenticate;
 
 impl Authenticate {
-    pub fn perform(connection: &mut Connection) -> Result<Option<User>, Box<dyn Error>> {
-        match connection.receive()? {
-            Authenticate::Authenticate => Authenticate::authenticate(connection),
-            Authenticate::Register => Authenticate::register(connection),
-            Authenticate::Reset => Authenticate::reset_password(connection),
-            Authenticate::Exit => Err("Client disconnected")?
+    /// # Returns
+    /// * `Option<(User, Session)>` - The logged in user and its session, `None` if the flow failed
+    pub async fn perform(request: AuthenticateRequest,
+                         connection: &mut Connection) -> Result<Option<(User, Session)>, Box<dyn Error + Send + Sync>> {
+        // A flow has a deadline, so a client can't keep the connection forever by never sending
+        // the next step, a pending email token can still be sent on a new connection
+        let flow = async {
+            let login = match request {
+                AuthenticateRequest::Authenticate => Authenticate::authenticate(connection).await?,
+                AuthenticateRequest::Register => Authenticate::register(connection).await?,
+                AuthenticateRequest::Reset => Authenticate::reset_password(connection).await?,
+                AuthenticateRequest::Resume => return Authenticate::resume(connection).await,
+                AuthenticateRequest::Verify => Authenticate::verify(connection).await?,
+                AuthenticateRequest::Exit => Err("Client disconnected")?
+            };
+
+            // Every login starts a new session, a resumption continues the lost one
+            Ok(login.map(|(user, auth_level)| {
+                let session = session::open(&user.email, auth_level);
+                (user, session)
+            }))
+        };
+
+        match timeout(CONFIG.flow_timeout, flow).await {
+            Ok(result) => result,
+            Err(_) => Err("Flow timed out".into()),
         }
     }
 
-    fn register(connection: &mut Connection) -> Result<Option<User>, Box<dyn Error>> {
+    async fn register(connection: &mut Connection) -> Result<Option<(User, AuthLevel)>, Box<dyn Error + Send + Sync>> {
+        // The client derives the verifier with the Argon2 parameters of the server
+        let password_params = CONFIG.password_params.to_phc();
+        connection.send(PasswordParamsData {
+            password_params: password_params.clone(),
+        }).await?;
+
         // Validate data
-        let register_data :RegisterData = connection.receive()?;
-        let mut error_message = "";
+        let register_data :RegisterData = connection.receive().await?;
+        let mut error = None;
 
         if !validate_email(&register_data.email) {
-            error_message = INVALID_EMAIL;
+            error = Some(StatusCode::InvalidEmail);
         }
 
-        if !validate_password(&register_data.password) {
-            error_message = INVALID_PASSWORD;
+        if register_data.verifier.is_empty() {
+            error = Some(StatusCode::InvalidPassword);
         }
 
         if !validate_public_key(&register_data.public_yubikey) {
-            error_message = INVALID_PUBLIC_KEY;
+            error = Some(StatusCode::InvalidPublicKey);
         }
 
-        // Verify if account exists
-        if Database::get(&register_data.email)?.is_some() {
-            error_message = ACCOUNT_EXISTING;
+        if !validate_key_label(&register_data.yubikey_label) {
+            error = Some(StatusCode::InvalidLabel);
         }
 
+        let attestation = match attest_yubikey(&register_data.public_yubikey, &register_data.attestation) {
+            Ok(attestation) => attestation,
+            Err(status) => {
+                error = Some(status);
+                None
+            }
+        };
+
         // Send response
-        if error_message != "" {
-            connection.send(&ServerResponse{
-                message: String::from(error_message),
-                success: false,
-            })?;
-            return Err(error_message.into());
+        if let Some(status) = error {
+            connection.send(ServerResponse{
+                status,
+            }).await?;
+            return Err(status.into());
         } else {
-            connection.send(&ServerResponse{
-                message: String::from(EMAIL_SENT),
-                success: true,
-            })?;
+            connection.send(ServerResponse{
+                status: StatusCode::EmailSent,
+            }).await?;
+        }
+
+        // An existing account gets the same response and only its owner is told, by email.
+        // No token is sent, so the next step fails like a wrong token.
+        if Database::get(&register_data.email)?.is_some() {
+            send_mail(&register_data.email,
+                      "Registration attempt",
+                      "Someone tried to register an account with this email, but it already has one. \
+                      If it was you, log in or reset your password, otherwise you can ignore this email.")?;
+            // Saved like the pending verification of a new account, so the response takes the same time
+            Database::save().await?;
+
+            let confirmation_data :UUIDData = connection.receive().await?;
+            return Authenticate::verify_email(connection,
+                                              VerificationPurpose::Registration,
+                                              &register_data.email,
+                                              &confirmation_data.uuid).await;
         }
 
+        // The account is kept with the token until the email is confirmed
+        // 2 FA is by default as false
+        let mut user = User {
+            email: register_data.email,
+            salt: register_data.salt,
+            verifier: vec![],
+            pepper_id: None,
+            password_params,
+            hash_password: String::new(),
+            public_yubikey: vec![],
+            yubikeys: vec![YubiKeyFactor::new(register_data.yubikey_label,
+                                              register_data.public_yubikey,
+                                              attestation)],
+            two_fa: false,
+            second_factors: default_second_factors(),
+            totp: None,
+            recovery_codes: vec![],
+            login_failures: LoginFailures::default(),
+        };
+        user.set_verifier(&register_data.verifier);
+
         // Send email for semantic validation
-        let uuid = send_token_email(&register_data.email,
+        let uuid = send_token_email(&user.email,
                               "Mail validation token",
                               "Here is the validation token")?;
+        let email = user.email.clone();
+        Database::insert_pending(PendingVerification::new(VerificationPurpose::Registration,
+                                                          &email,
+                                                          &uuid,
+                                                          Some(user))).await?;
 
         // Wait for email token
-        let confirmation_data :UUIDData = connection.receive()?;
-
-        // Send result message
-        validate_email_uuid(connection,
-                            &uuid,
-                            &confirmation_data.uuid,
-                            ACCOUNT_REGISTERED)?;
+        let confirmation_data :UUIDData = connection.receive().await?;
+        Authenticate::verify_email(connection, VerificationPurpose::Registration, &email, &confirmation_data.uuid).await
+    }
 
-        // Hash password for DB
-        let (salt, hash_password) = hash_password(&register_data.password);
+    /// The email token of a registration or a password reset whose connection was lost
+    async fn verify(connection: &mut Connection) -> Result<Option<(User, AuthLevel)>, Box<dyn Error + Send + Sync>> {
+        let verification_data :VerificationData = connection.receive().await?;
+        Authenticate::verify_email(connection,
+                                   verification_data.purpose,
+                                   &verification_data.email,
+                                   &verification_data.uuid).await
+    }
 
-        // Register in db
-        // 2 FA is by default as false
-        let user = User {
-            email: register_data.email,
-            salt,
-            hash_password,
-            public_yubikey: register_data.public_yubikey,
-            two_fa: false,
+    /// Check the email token against the pending verification and finish its flow.
+    /// A wrong token counts as an attempt, the verification can be tried again on a new connection.
+    async fn verify_email(connection: &mut Connection,
+                          purpose: VerificationPurpose,
+                          email: &str,
+                          uuid: &str) -> Result<Option<(User, AuthLevel)>, Box<dyn Error + Send + Sync>> {
+        let pending = match validate_uuid(uuid) {
+            true => Database::consume_pending(purpose, email, uuid).await?,
+            false => None,
+        };
+        let pending = match pending {
+            Some(pending) => pending,
+            None => {
+                connection.send(ServerResponse{
+                    status: StatusCode::BadUuid,
+                }).await?;
+                return Err(StatusCode::BadUuid.into());
+            }
         };
 
-        Database::insert(&user)?;
-        Ok(Some(user))
+        match pending.user {
+            Some(user) => Authenticate::finish_registration(connection, user).await,
+            None => Authenticate::finish_reset(connection, email).await,
+        }
+    }
+
+    async fn finish_registration(connection: &mut Connection,
+                                 mut user: User) -> Result<Option<(User, AuthLevel)>, Box<dyn Error + Send + Sync>> {
+        // The email may have been registered by another verification in the meantime
+        if Database::get(&user.email)?.is_some() {
+            connection.send(ServerResponse{
+                status: StatusCode::AccountExisting,
+            }).await?;
+            return Err(StatusCode::AccountExisting.into());
+        }
+
+        // Register in db
+        let recovery_codes = recovery::generate_codes();
+        user.recovery_codes = recovery_codes.iter().map(|code| recovery::hash_code(code)).collect();
+        Database::insert(&user).await?;
+
+        connection.send(ServerResponse{
+            status: StatusCode::AccountRegistered,
+        }).await?;
+
+        // The codes are only shown once, the server keeps their hashes
+        connection.send(RecoveryCodesData {
+            codes: recovery_codes,
+        }).await?;
+        Ok(Some((user, AuthLevel::OneFactor)))
     }
 
-    fn authenticate(connection: &mut Connection) -> Result<Option<User>, Box<dyn Error>> {
-        let email_data :EmailData = connection.receive()?;
+    async fn authenticate(connection: &mut Connection) -> Result<Option<(User, AuthLevel)>, Box<dyn Error + Send + Sync>> {
+        let start_data :SrpStartData = connection.receive().await?;
 
         // Default user
-        let mut user = User {
-            email: "default@default.default".to_string(),
-            salt: [0; 16],
-            hash_password: "default".to_string(),
-            public_yubikey: vec![],
-            two_fa: false
-        };
-        let mut user_salt: [u8; 16] = [0; 16];
+        let mut user = User::unknown();
         let mut valid_user = false;
 
         // We always do all the process of checking even if there is no user
         // because we always want the same time of response
-        if validate_email(&email_data.email) {
+        if validate_email(&start_data.email) {
             // Get user in BD
-            match Database::get(&email_data.email)? {
+            match Database::get(&start_data.email)? {
                 Some(user_found) => {
                     valid_user = true;
-                    user_salt = user_found.salt;
                     user = user_found;
                 },
                 None => valid_user = false,
             }
         }
 
-        // Creating challenge
-        let mut challenge: [u8; 16] = [0; 16];
-        generate_random_16_bytes(&mut challenge);
-
-        // Sending challenge
-        connection.send(&ChallengeWithSaltData {
-            challenge,
-            salt: user_salt,
-        })?;
-
-        // Creating the answer to challenge
-        let response;
-        match hashmac_sha256(&challenge, &user.hash_password) {
-            Ok(response_hash) => response = response_hash,
-            Err(error) => {
-                return Err(format!("{}", error).into());
-            },
+        // An email without account gets the same salt and parameters on every request, like an account,
+        // the parameters in use are counted for every login so it takes the same time
+        let in_use = Database::password_params_in_use()?;
+        if !valid_user {
+            let salt_key = Database::salt_key()?;
+            user.salt = derive_salt(salt_key.as_bytes(), &start_data.email);
+            user.password_params = derive_password_params(salt_key.as_bytes(),
+                                                          &start_data.email,
+                                                          &CONFIG.password_params.to_phc(),
+                                                          &in_use);
         }
 
-        // Send result response to challenge
-        let response_data :ResponseData = connection.receive()?;
-        if response_data.response != response || !valid_user {
-            connection.send(&ServerResponseTwoFA{
-                message: AUTH_FAIL.to_string(),
-                success: false,
-                two_fa: false
-            })?;
+        // A locked account is refused at the end of the flow whatever the proof,
+        // an email without account is locked the same way
+        let locked = if valid_user {
+            user.login_failures.is_locked()
+        } else {
+            lockout::unknown_failures(&start_data.email).is_locked()
+        };
+
+        // A verifier that can't be unwrapped is refused like a wrong password
+        let verifier = user.verifier().unwrap_or_else(|error| {
+            println!("{}", error);
+            dummy_verifier()
+        });
+
+        // Creating challenge, bound to this connection
+        let challenge = Challenge::new(ChallengePurpose::Login, &CONFIG.server_id, connection.id, now());
+        let b = srp::generate_secret();
+
+        // Sending challenge
+        connection.send(SrpChallengeData {
+            salt: user.salt,
+            password_params: user.password_params.clone(),
+            b_pub: srp_server_public(&b, &verifier).await?,
+            challenge: challenge.clone(),
+        }).await?;
+
+        // Check the proof of the client, the server only knows the verifier
+        let response_data :ResponseData = connection.receive().await?;
+        let proof = srp_verify_client(&b, &verifier, &start_data.a_pub, &response_data.response).await?;
+        if locked {
+            connection.send(ServerResponseTwoFA{
+                status: StatusCode::Locked,
+                two_fa: false,
+                proof: vec![],
+                factors: vec![],
+            }).await?;
             return Ok(None);
         }
 
+        let proof = match proof {
+            Some(proof) if valid_user => proof,
+            _ => {
+                connection.send(ServerResponseTwoFA{
+                    status: StatusCode::AuthFail,
+                    two_fa: false,
+                    proof: vec![],
+                    factors: vec![],
+                }).await?;
+                // Recorded after the response, so saving the account doesn't make it slower than an unknown one
+                if valid_user {
+                    Authenticate::record_failure(&user.email).await?;
+                } else if validate_email(&start_data.email) {
+                    lockout::record_unknown_failure(&start_data.email);
+                }
+                return Ok(None);
+            }
+        };
+
         // Send if auth is success or still need a 2FA
         if !user.two_fa {
-            connection.send(&ServerResponseTwoFA{
-                message: AUTH_SUCCESS.to_string(),
-                success: true,
-                two_fa: false
-            })?;
-            return Ok(Some(user));
+            connection.send(ServerResponseTwoFA{
+                status: StatusCode::AuthSuccess,
+                two_fa: false,
+                proof,
+                factors: vec![],
+            }).await?;
+            Authenticate::record_success(&mut user).await?;
+            Authenticate::update_verifier(connection, &mut user, &verifier).await?;
+            return Ok(Some((user, AuthLevel::OneFactor)));
         } else {
-            connection.send(&ServerResponseTwoFA{
-                message: AUTH_TWO_FA.to_string(),
-                success: true,
-                two_fa: true
-            })?;
+            connection.send(ServerResponseTwoFA{
+                status: StatusCode::AuthTwoFA,
+                two_fa: true,
+                proof,
+                factors: user.available_factors(),
+            }).await?;
         }
 
-        // Second factor authentification
+        // Second factor authentification, with any factor of the user
         // We don't send a new challenge because we use same challenge than before
-        let two_fa_response :ResponseData = connection.receive()?;
-        if verify_challenge_yubikey(&user.public_yubikey, &challenge, &two_fa_response.response)? {
-            connection.send(&ServerResponse {
-                message: AUTH_SUCCESS.to_string(),
-                success: true,
-            })?;
-            Ok(Some(user))
+        let two_fa_response :SecondFactorResponse = connection.receive().await?;
+        let factors = user.available_factors();
+        let status = Authenticate::verify_second_factor(&mut user,
+                                                        true,
+                                                        &challenge,
+                                                        ChallengePurpose::Login,
+                                                        &connection.id,
+                                                        two_fa_response,
+                                                        &factors).await?;
+
+        connection.send(ServerResponse {
+            status,
+        }).await?;
+        if status == StatusCode::AuthSuccess {
+            Authenticate::record_success(&mut user).await?;
+            Authenticate::update_verifier(connection, &mut user, &verifier).await?;
+            Ok(Some((user, AuthLevel::TwoFactor)))
         } else {
-            connection.send(&ServerResponse {
-                message: WRONG_KEY.to_string(),
-                success: false,
-            })?;
+            Authenticate::record_failure(&user.email).await?;
             Ok(None)
         }
     }
 
-    fn reset_password(connection: &mut Connection) -> Result<Option<User>, Box<dyn Error>> {
-        // Validate email
-        let email_data:EmailData = connection.receive()?;
-        let mut valid_email = false;
-        let mut reset_user = None;
-
-        if validate_email(&email_data.email) {
-            // Verify if account exists
-            reset_user = Database::get(&email_data.email)?;
-            if reset_user.is_some() {
-                valid_email = true;
+    /// Once logged in, the verifier of the account is brought up to date: derived again by the client
+    /// if its Argon2 parameters are outdated, or wrapped again if its pepper key isn't the current one.
+    /// The parameters of the server are only sent here, so the challenge doesn't tell whether the account exists.
+    async fn update_verifier(connection: &mut Connection,
+                             user: &mut User,
+                             verifier: &[u8]) -> Result<(), Box<dyn Error + Send + Sync>> {
+        // The client compares them with the ones of the challenge, and sends a new verifier if they differ
+        connection.send(PasswordParamsData {
+            password_params: CONFIG.password_params.to_phc(),
+        }).await?;
+        if Argon2Params::from_phc(&user.password_params) != Ok(CONFIG.password_params) {
+            return Authenticate::rehash(connection, user).await;
+        }
+        if pepper::needs_rewrap(user.pepper_id) {
+            let logged_in = user.verifier.clone();
+            user.set_verifier(verifier);
+            let (pepper_id, wrapped) = (user.pepper_id, user.verifier.clone());
+            // Unless the password has been reset in the meantime
+            let rewrapped = Database::update(&user.email, move |user| {
+                let unchanged = user.verifier == logged_in;
+                if unchanged {
+                    (user.pepper_id, user.verifier) = (pepper_id, wrapped);
+                }
+                unchanged
+            }).await?;
+            if rewrapped == Some(true) {
+                println!("Verifier of {} wrapped again with the current pepper key", user.email);
             }
         }
+        Ok(())
+    }
 
-        let mut challenge: [u8; 16];
+    /// The client sends a verifier of the same password, derived with the Argon2 parameters of the server
+    async fn rehash(connection: &mut Connection, user: &mut User) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let verifier_data :VerifierData = connection.receive().await?;
+        if verifier_data.verifier.is_empty() {
+            return Err(StatusCode::InvalidPassword.into());
+        }
 
-        if valid_email {
-            // Verify that is the good user with 2FA
-            // Creating challenge
-            challenge = [0; 16];
-            generate_random_16_bytes(&mut challenge);
+        let logged_in = user.verifier.clone();
+        user.salt = verifier_data.salt;
+        user.set_verifier(&verifier_data.verifier);
+        user.password_params = CONFIG.password_params.to_phc();
+        let rehashed = user.clone();
+        // Unless the password has been reset in the meantime
+        let updated = Database::update(&user.email, move |user| {
+            let unchanged = user.verifier == logged_in;
+            if unchanged {
+                user.salt = rehashed.salt;
+                (user.pepper_id, user.verifier) = (rehashed.pepper_id, rehashed.verifier);
+                user.password_params = rehashed.password_params;
+            }
+            unchanged
+        }).await?;
+        if updated == Some(true) {
+            println!("Password of {} rehashed with {}", user.email, user.password_params);
+        }
+        Ok(())
+    }
 
-            // Confirm email validation
-            connection.send(&ServerResponse{
-                message: String::from(VALID_EMAIL),
-                success: true,
-            })?;
+    /// Check the answer of the user to the second factor step, with one of the `allowed` factors.
+    /// A TOTP code or a recovery code is spent at once, so it can't be replayed on another connection.
+    /// # Arguments
+    /// * `valid_user` - `false` for the dummy user of an unknown email, it never passes and the database isn't used
+    /// # Returns
+    /// * `StatusCode` - `AuthSuccess`, or the error of the factor used
+    async fn verify_second_factor(user: &mut User,
+                                  valid_user: bool,
+                                  challenge: &Challenge,
+                                  purpose: ChallengePurpose,
+                                  session_id: &[u8; 16],
+                                  response: SecondFactorResponse,
+                                  allowed: &[SecondFactorType]) -> Result<StatusCode, Box<dyn Error + Send + Sync>> {
+        let status = match response {
+            // Any registered key can sign, its last use is saved
+            SecondFactorResponse::YubiKey(signature) => {
+                // A key may have been removed since the flow started
+                if valid_user {
+                    if let Some(saved_user) = Database::get(&user.email)? {
+                        user.yubikeys = saved_user.yubikeys;
+                    }
+                }
+                let yubikey = if allowed.contains(&SecondFactorType::YubiKey) {
+                    verify_challenge_yubikey(&mut user.yubikeys, challenge, purpose, session_id, &signature)
+                } else {
+                    None
+                };
+                match yubikey {
+                    Some(index) if valid_user => {
+                        let signer = user.yubikeys[index].clone();
+                        println!("{} signed with the YubiKey {}", user.email, signer.label);
+                        Database::update(&user.email, move |user| {
+                            if let Some(yubikey) = user.yubikeys.iter_mut().find(|yubikey| yubikey.public_key == signer.public_key) {
+                                yubikey.last_used = signer.last_used;
+                            }
+                        }).await?;
+                        StatusCode::AuthSuccess
+                    },
+                    _ => StatusCode::WrongKey,
+                }
+            },
+            // The code is checked and spent on the saved account, so parallel flows can't all accept it
+            SecondFactorResponse::Totp(code) => {
+                let step = if valid_user && allowed.contains(&SecondFactorType::Totp) {
+                    Database::update(&user.email, move |user| {
+                        let factor = user.totp.as_mut()?;
+                        factor.last_step = verify_totp(factor.secret.as_bytes(), &code, Some(factor.last_step))?;
+                        Some(factor.last_step)
+                    }).await?.flatten()
+                } else {
+                    None
+                };
+                match step {
+                    Some(step) => {
+                        if let Some(factor) = user.totp.as_mut() {
+                            factor.last_step = step;
+                        }
+                        StatusCode::AuthSuccess
+                    },
+                    None => StatusCode::WrongCode,
+                }
+            },
+            // Removed from the saved account, the code is only accepted by the flow that removes it
+            SecondFactorResponse::RecoveryCode(code) => {
+                let hash = recovery::hash_code(&code);
+                let remaining = if valid_user && allowed.contains(&SecondFactorType::RecoveryCode) {
+                    Database::update(&user.email, move |user| {
+                        let index = user.recovery_codes.iter().position(|recovery_code| recovery_code.matches(&hash))?;
+                        user.recovery_codes.remove(index);
+                        Some(user.recovery_codes.clone())
+                    }).await?.flatten()
+                } else {
+                    None
+                };
+                match remaining {
+                    Some(remaining) => {
+                        user.recovery_codes = remaining;
+                        StatusCode::AuthSuccess
+                    },
+                    None => StatusCode::WrongRecoveryCode,
+                }
+            },
+        };
+        Ok(status)
+    }
 
-            // Sending challenge
-            connection.send(&ChallengeData {
-                challenge,
-            })?;
+    /// Counted on the saved account, so parallel flows all count their failure
+    async fn record_failure(email: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+        Database::update(email, |user| user.login_failures.record_failure()).await?;
+        Ok(())
+    }
+
+    /// Failures may have been counted by other connections since the flow started,
+    /// so the saved account is cleared even if the copy of the flow has none
+    async fn record_success(user: &mut User) -> Result<(), Box<dyn Error + Send + Sync>> {
+        user.login_failures.record_success();
+        Database::update(&user.email, |user| user.login_failures.record_success()).await?;
+        Ok(())
+    }
+
+    /// Log in again with the ticket of a session whose connection has been lost
+    async fn resume(connection: &mut Connection) -> Result<Option<(User, Session)>, Box<dyn Error + Send + Sync>> {
+        let ticket_data :TicketData = connection.receive().await?;
+
+        // The account may have been removed or changed since the ticket was issued
+        let login = match session::resume(&ticket_data.ticket) {
+            Some(session) => Database::get(&session.email)?.map(|user| (user, session)),
+            None => None,
+        };
+
+        connection.send(ServerResponse {
+            status: if login.is_some() { StatusCode::AuthSuccess } else { StatusCode::ResumeFail },
+        }).await?;
+        Ok(login)
+    }
+
+    async fn reset_password(connection: &mut Connection) -> Result<Option<(User, AuthLevel)>, Box<dyn Error + Send + Sync>> {
+        // Validate email, only its format is told to the client
+        let email_data:EmailData = connection.receive().await?;
+        if !validate_email(&email_data.email) {
+            connection.send(ServerResponse{
+                status: StatusCode::InvalidEmail,
+            }).await?;
+            return Err(StatusCode::InvalidEmail.into());
+        }
+
+        // An email without account goes through the same steps with a dummy user,
+        // whose key nobody has, so both get the same responses
+        let (mut user, valid_user) = match Database::get(&email_data.email)? {
+            Some(user) => (user, true),
+            None => (User::unknown(), false),
+        };
+
+        // Verify that is the good user with 2FA
+        // Creating challenge, bound to this connection
+        let challenge = Challenge::new(ChallengePurpose::PasswordReset, &CONFIG.server_id, connection.id, now());
+
+        // Confirm email validation
+        connection.send(ServerResponse{
+            status: StatusCode::ValidEmail,
+        }).await?;
+
+        // Sending challenge
+        connection.send(ChallengeData {
+            challenge: challenge.clone(),
+        }).await?;
+
+        // Receive response, the YubiKey or a recovery code if the key is lost,
+        // this step is locked like the login
+        let response_data: SecondFactorResponse = connection.receive().await?;
+        let locked = if valid_user {
+            user.login_failures.is_locked()
         } else {
-            connection.send(&ServerResponse{
-                message: String::from(INVALID_EMAIL),
-                success: false,
-            })?;
-            return Err(INVALID_EMAIL.into());
+            lockout::unknown_failures(&email_data.email).is_locked()
+        };
+        if locked {
+            connection.send(ServerResponse{
+                status: StatusCode::Locked,
+            }).await?;
+            return Err(StatusCode::Locked.into());
         }
 
-        // Receive response
-        let response_data: ResponseData = connection.receive()?;
-        if verify_challenge_yubikey(&reset_user.as_ref().unwrap().public_yubikey, &challenge, &response_data.response)? {
-            connection.send(&ServerResponse{
-                message: String::from(EMAIL_SENT),
-                success: true,
-            })?;
+        let allowed = [SecondFactorType::YubiKey, SecondFactorType::RecoveryCode];
+        let status = Authenticate::verify_second_factor(&mut user,
+                                                        valid_user,
+                                                        &challenge,
+                                                        ChallengePurpose::PasswordReset,
+                                                        &connection.id,
+                                                        response_data,
+                                                        &allowed).await?;
+        if status == StatusCode::AuthSuccess {
+            connection.send(ServerResponse{
+                status: StatusCode::EmailSent,
+            }).await?;
         } else {
-            connection.send(&ServerResponse{
-                message: String::from(WRONG_KEY),
-                success: false,
-            })?;
-            return Err(WRONG_KEY.into());
+            connection.send(ServerResponse{
+                status,
+            }).await?;
+            // Recorded after the response, so saving the account doesn't make it slower than an unknown one
+            if valid_user {
+                Authenticate::record_failure(&user.email).await?;
+            } else {
+                lockout::record_unknown_failure(&email_data.email);
+            }
+            return Err(status.into());
         }
 
         // Send reset email
         let uuid = send_token_email(&email_data.email,
                                     "Reset password mail",
                                     "Here is the reset password token ")?;
+        Database::insert_pending(PendingVerification::new(VerificationPurpose::PasswordReset,
+                                                          &email_data.email,
+                                                          &uuid,
+                                                          None)).await?;
 
-        let uuid_data :UUIDData = connection.receive()?;
-
-        // Send result message
-        validate_email_uuid(connection,
-                            &uuid,
-                            &uuid_data.uuid,
-                            CORRECT_UUID)?;
-
-        let password_data :PasswordData = connection.receive()?;
+        let uuid_data :UUIDData = connection.receive().await?;
+        Authenticate::verify_email(connection, VerificationPurpose::PasswordReset, &email_data.email, &uuid_data.uuid).await
+    }
 
-        if !validate_password(&password_data.password) {
-            connection.send(&ServerResponse{
-                message: String::from(INVALID_PASSWORD),
-                success: false,
-            })?;
-            Err(INVALID_PASSWORD.into())
-        } else {
-            // Update in db
-            let (salt, hash_password) = hash_password(&password_data.password);
-            match reset_user {
-                Some(mut user_db) => {
-                    user_db.hash_password = hash_password;
-                    user_db.salt = salt;
-                    Database::insert(&user_db)?;
-                    Ok(Some(user_db))
-                },
-                None => return Err(INVALID_EMAIL.into()),
-            }
+    /// The email token is confirmed, the user chooses the new password
+    async fn finish_reset(connection: &mut Connection,
+                          email: &str) -> Result<Option<(User, AuthLevel)>, Box<dyn Error + Send + Sync>> {
+        connection.send(ServerResponse{
+            status: StatusCode::CorrectUuid,
+        }).await?;
+
+        // The client checks the strength of the password, the server only gets its verifier
+        let password_params = CONFIG.password_params.to_phc();
+        connection.send(PasswordParamsData {
+            password_params: password_params.clone(),
+        }).await?;
+        let verifier_data :VerifierData = connection.receive().await?;
+        if verifier_data.verifier.is_empty() {
+            connection.send(ServerResponse{
+                status: StatusCode::InvalidPassword,
+            }).await?;
+            return Err(StatusCode::InvalidPassword.into());
+        }
+        connection.send(ServerResponse{
+            status: StatusCode::PasswordChanged,
+        }).await?;
+
+        // Update in db, the verification may be finished long after it started
+        let updated = Database::update(email, move |user| {
+            user.salt = verifier_data.salt;
+            user.set_verifier(&verifier_data.verifier);
+            user.password_params = password_params;
+            user.hash_password.clear();
+            user.login_failures.record_success();
+            user.clone()
+        }).await?;
+        match updated {
+            Some(user) => {
+                // Sessions opened with the old password end
+                session::revoke_user(&user.email);
+                Ok(Some((user, AuthLevel::TwoFactor)))
+            },
+            None => Err(StatusCode::InvalidEmail.into()),
         }
     }
 }
 
-// I could implement system of number of try to lock an account or not but it wasn't asked
-// so it is not implemented, but could easily be done with 2 more fields in BD (nb_tries and locked)
-
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct User {
     pub email: String,
     pub salt: [u8; 16],
+    /// SRP verifier of the password, it can't be used to log in.
+    /// It is encrypted with the pepper key `pepper_id` of the server, see `verifier()`.
+    #[serde(default)]
+    pub verifier: Vec<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pepper_id: Option<u32>,
+    /// Argon2 parameters of the SRP password, as a PHC string
+    #[serde(default = "legacy_password_params")]
+    pub password_params: String,
+    /// Argon2 hash of the password of the accounts created before SRP, it is enough to log in,
+    /// so it is replaced by a verifier when the server starts (see `Database::migrate`)
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub hash_password: String,
+    /// Public key of the accounts created with a single YubiKey, it is moved to `yubikeys`
+    /// when the server starts (see `Database::migrate`)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub public_yubikey: Vec<u8>,
-    pub two_fa: bool
+    /// Registered YubiKeys, any of them can sign the challenges
+    #[serde(default)]
+    pub yubikeys: Vec<YubiKeyFactor>,
+    pub two_fa: bool,
+    /// Second factors enrolled, any of them can be used when `two_fa` is enabled
+    #[serde(default = "default_second_factors")]
+    pub second_factors: Vec<SecondFactorType>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub totp: Option<TotpFactor>,
+    /// Hashes of the recovery codes left, a code is removed once used
+    #[serde(default)]
+    pub recovery_codes: Vec<SecretBytes>,
+    #[serde(default)]
+    pub login_failures: LoginFailures,
+}
+
+impl User {
+    /// Stand-in for an email without account, it goes through the same checks as an account
+    /// and never passes them
+    pub fn unknown() -> User {
+        User {
+            email: UNKNOWN_EMAIL.to_string(),
+            salt: [0; 16],
+            verifier: dummy_verifier(),
+            pepper_id: None,
+            password_params: CONFIG.password_params.to_phc(),
+            hash_password: String::new(),
+            public_yubikey: vec![],
+            yubikeys: vec![YubiKeyFactor::new(String::from("YubiKey"), dummy_public_key(), None)],
+            two_fa: false,
+            second_factors: default_second_factors(),
+            totp: None,
+            recovery_codes: vec![],
+            login_failures: LoginFailures::default(),
+        }
+    }
+
+    /// The SRP verifier unwrapped with the pepper
+    pub fn verifier(&self) -> Result<Vec<u8>, String> {
+        pepper::unwrap(&self.email, self.pepper_id, &self.verifier)
+    }
+
+    /// Store the verifier wrapped with the current pepper key
+    pub fn set_verifier(&mut self, verifier: &[u8]) {
+        (self.pepper_id, self.verifier) = pepper::wrap(&self.email, verifier);
+    }
+
+    /// The enrolled factors, and the recovery codes as long as some are left
+    pub fn available_factors(&self) -> Vec<SecondFactorType> {
+        let mut factors = self.second_factors.clone();
+        if !self.recovery_codes.is_empty() {
+            factors.push(SecondFactorType::RecoveryCode);
+        }
+        factors
+    }
+}
+
+/// A YubiKey of the user, times are UNIX timestamps in seconds
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct YubiKeyFactor {
+    pub label: String,
+    pub public_key: Vec<u8>,
+    pub created: u64,
+    pub last_used: Option<u64>,
+    /// Serial, firmware and policies certified by the YubiKey, `None` for the keys registered without attestation
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub attestation: Option<KeyAttestation>,
+}
+
+impl YubiKeyFactor {
+    pub fn new(label: String, public_key: Vec<u8>, attestation: Option<KeyAttestation>) -> YubiKeyFactor {
+        YubiKeyFactor {
+            label,
+            public_key,
+            created: now(),
+            last_used: None,
+            attestation,
+        }
+    }
+
+    pub fn info(&self) -> YubiKeyInfo {
+        YubiKeyInfo {
+            label: self.label.clone(),
+            created: self.created,
+            last_used: self.last_used,
+            serial: self.attestation.as_ref().map(|attestation| attestation.serial),
+        }
+    }
+}
+
+/// Secret of the authenticator app of the user
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TotpFactor {
+    pub secret: SecretBytes,
+    /// Time step of the last code accepted, older codes are refused
+    pub last_step: u64,
+}
+
+/// The accounts saved without parameters were hashed with the defaults of the Argon2 crate
+fn legacy_password_params() -> String {
+    Argon2Params::LEGACY.to_phc()
+}
+
+/// Every account is registered with a YubiKey
+fn default_second_factors() -> Vec<SecondFactorType> {
+    vec![SecondFactorType::YubiKey]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unknown_user() {
+        // Its email can't be registered, so the dummy user never reads or changes an account
+        assert!(!validate_email(&User::unknown().email));
+    }
 }
@@ -1,23 +1,57 @@
 use uuid::Uuid;
 use std::error::Error;
-use p256::ecdsa::VerifyingKey;
+use std::time::{SystemTime, UNIX_EPOCH};
+use p256::ecdsa::{SigningKey, VerifyingKey};
 use p256::ecdsa::signature::Verifier;
 use p256::EncodedPoint;
+use tokio::task;
 
-use app_tools::security::crypto::*;
-use app_tools::communication::data::ServerResponse;
-use app_tools::input_validation::uuid::validate_uuid;
-use app_tools::communication::messages::{BAD_UUID, INVALID_PUBLIC_KEY};
+use app_tools::security::{srp, totp};
+use app_tools::security::challenge::{Challenge, ChallengePurpose};
+use app_tools::security::crypto::generate_random_bytes;
+use app_tools::communication::data::AttestationData;
+use app_tools::communication::status::StatusCode;
 
 
-use crate::connection::Connection;
+use crate::attestation::{self, KeyAttestation};
+use crate::authentication::YubiKeyFactor;
+use crate::config::CONFIG;
 use crate::mailer::send_mail;
 
-pub fn hash_password(password: &str) -> ([u8; 16], String) {
-    let mut salt: [u8; 16] = [0; 16];
-    generate_random_16_bytes(&mut salt);
-    let hash_password = hash_argon2(password, &mut salt);
-    (salt, hash_password)
+lazy_static! {
+    /// Unknown accounts get a `B` computed with a verifier too, so they can't be told apart
+    static ref DUMMY_VERIFIER: Vec<u8> = srp::compute_verifier("", &generate_string_uuid(), &[0; 16]);
+    /// Unknown accounts get their challenge checked against a key too, its private key is dropped right away
+    static ref DUMMY_PUBLIC_KEY: Vec<u8> = {
+        let mut secret = [0; 32];
+        generate_random_bytes(&mut secret);
+        let key = SigningKey::from_bytes(&secret).expect("Random bytes are a valid P-256 scalar");
+        key.verifying_key().to_encoded_point(false).as_bytes().to_vec()
+    };
+}
+
+pub fn dummy_verifier() -> Vec<u8> {
+    DUMMY_VERIFIER.clone()
+}
+
+pub fn dummy_public_key() -> Vec<u8> {
+    DUMMY_PUBLIC_KEY.clone()
+}
+
+/// The modular exponentiations of SRP are slow, they are computed on the blocking pool
+pub async fn srp_server_public(b: &[u8], verifier: &[u8]) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
+    let (b, verifier) = (b.to_vec(), verifier.to_vec());
+    Ok(task::spawn_blocking(move || srp::server_public(&b, &verifier)).await?)
+}
+
+/// # Returns
+/// * `Option<Vec<u8>>` - The proof of the server, `None` if the proof of the client is wrong
+pub async fn srp_verify_client(b: &[u8],
+                               verifier: &[u8],
+                               a_pub: &[u8],
+                               proof: &[u8]) -> Result<Option<Vec<u8>>, Box<dyn Error + Send + Sync>> {
+    let (b, verifier, a_pub, proof) = (b.to_vec(), verifier.to_vec(), a_pub.to_vec(), proof.to_vec());
+    Ok(task::spawn_blocking(move || srp::verify_client(&b, &verifier, &a_pub, &proof).ok()).await?)
 }
 
 pub fn generate_string_uuid() -> String {
@@ -25,32 +59,13 @@ pub fn generate_string_uuid() -> String {
     uuid.as_hyphenated().to_string()
 }
 
-pub fn send_token_email(dst: &str, subject: &str, token_message: &str) -> Result<String, Box<dyn Error>> {
+pub fn send_token_email(dst: &str, subject: &str, token_message: &str) -> Result<String, Box<dyn Error + Send + Sync>> {
     let uuid = generate_string_uuid();
     let message = format!("{} : {}", token_message, uuid);
     send_mail(dst, subject, &message)?;
     Ok(uuid)
 }
 
-pub fn validate_email_uuid(connection: &mut Connection,
-                           uuid_to_match: &str,
-                           uuid_to_test: &str,
-                           success_message: &str) -> Result<(), Box<dyn Error>> {
-    if !validate_uuid(uuid_to_test) || uuid_to_test != uuid_to_match {
-        connection.send(&ServerResponse{
-            message: String::from(BAD_UUID),
-            success: false,
-        })?;
-        return Err(BAD_UUID.into());
-    } else {
-        connection.send(&ServerResponse{
-            message: String::from(success_message),
-            success: true,
-        })?;
-    }
-    Ok(())
-}
-
 pub fn validate_public_key(public_key: &Vec<u8>) -> bool {
     return match VerifyingKey::from_sec1_bytes(public_key) {
         Ok(_) => true,
@@ -58,11 +73,49 @@ pub fn validate_public_key(public_key: &Vec<u8>) -> bool {
     }
 }
 
-pub fn verify_challenge_yubikey(public_yubikey: &Vec<u8>, challenge: &[u8], response: &[u8]) -> Result<bool, Box<dyn Error>> {
+/// Check the attestation of a new key, it is ignored if the attestations aren't configured
+/// # Returns
+/// * `Option<KeyAttestation>` - What the YubiKey certifies, `None` if the key isn't attested
+/// # Errors
+/// * `StatusCode::InvalidAttestation` - The attestation is wrong, or missing and `REQUIRE_ATTESTATION` is set
+pub fn attest_yubikey(public_key: &[u8], attestation: &Option<AttestationData>) -> Result<Option<KeyAttestation>, StatusCode> {
+    match attestation {
+        _ if !attestation::enabled() => Ok(None),
+        Some(attestation) => attestation::verify(public_key, attestation).map(Some).map_err(|error| {
+            println!("{}", error);
+            StatusCode::InvalidAttestation
+        }),
+        None if CONFIG.require_attestation => Err(StatusCode::InvalidAttestation),
+        None => Ok(None),
+    }
+}
+
+/// Check every field of the challenge against the step and the session it is expected for, and its age,
+/// then its signature with every key of the user. A malformed signature is a wrong one.
+/// # Returns
+/// * `Option<usize>` - The index of the key that signed, its last use is updated
+pub fn verify_challenge_yubikey(yubikeys: &mut [YubiKeyFactor],
+                                challenge: &Challenge,
+                                purpose: ChallengePurpose,
+                                session_id: &[u8; 16],
+                                response: &[u8]) -> Option<usize> {
+    if let Err(error) = challenge.check(purpose, &CONFIG.server_id, session_id, now(), CONFIG.challenge_lifetime.as_secs()) {
+        println!("{}", error);
+        return None;
+    }
+
+    let signed = challenge.to_bytes();
+    let index = yubikeys.iter()
+        .position(|yubikey| verify_signature_yubikey(&yubikey.public_key, &signed, response).unwrap_or(false))?;
+    yubikeys[index].last_used = Some(now());
+    Some(index)
+}
+
+pub fn verify_signature_yubikey(public_yubikey: &[u8], challenge: &[u8], response: &[u8]) -> Result<bool, Box<dyn Error + Send + Sync>> {
     let encoded_point: EncodedPoint = match EncodedPoint::from_bytes(public_yubikey){
         Ok(encoded_point) => encoded_point,
         Err(_) => {
-            return Err(INVALID_PUBLIC_KEY.into());
+            return Err(StatusCode::InvalidPublicKey.into());
         },
     };
     let verifying_key = p256::ecdsa::VerifyingKey::from_encoded_point(&encoded_point)?;
@@ -71,4 +124,19 @@ pub fn verify_challenge_yubikey(public_yubikey: &Vec<u8>, challenge: &[u8], resp
         Ok(_) => Ok(true),
         Err(_) => Ok(false),
     };
+}
+
+/// UNIX timestamp in seconds
+pub fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |duration| duration.as_secs())
+}
+
+/// Check a code of the authenticator app, `TOTP_SKEW` steps are accepted around the current one
+/// # Arguments
+/// * `last_step` - Step of the last code accepted, a code can't be used twice
+/// # Returns
+/// * `Option<u64>` - The step of the code, `None` if it is wrong or already used
+pub fn verify_totp(secret: &[u8], code: &str, last_step: Option<u64>) -> Option<u64> {
+    totp::verify(secret, code, now(), CONFIG.totp_skew)
+        .filter(|step| last_step.is_none_or(|last| *step > last))
 }
\ No newline at end of file
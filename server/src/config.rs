@@ -0,0 +1,275 @@
+use clap::Parser;
+use envfile::EnvFile;
+use std::error::Error;
+use std::path::Path;
+use std::time::Duration;
+use app_tools::communication::framing::DEFAULT_MAX_FRAME_SIZE;
+use app_tools::config::{parse_bool, parse_number, ConfigRef};
+use app_tools::security::crypto::Argon2Params;
+use crate::rate_limit::RateLimit;
+
+const DEFAULT_CONFIG_PATH: &str = "./.env";
+
+/// Settings of the server, loaded from the default env file if `Config::init` hasn't been called
+pub static CONFIG: ConfigRef<Config> = ConfigRef::new(|| Config::load(DEFAULT_CONFIG_PATH));
+
+/// Command line arguments, they override the values of the env file
+#[derive(Parser, Debug)]
+#[command(about = "Authentication server")]
+pub struct Args {
+    /// Env file with the server settings [default: ./.env]
+    #[arg(long, value_name = "PATH")]
+    pub config: Option<String>,
+
+    /// Address to listen on, IPv4 or IPv6 with port (e.g. [::1]:8080), can be repeated
+    #[arg(long, value_name = "ADDR")]
+    pub listen: Vec<String>,
+
+    /// Unix socket to listen on, in addition to the TCP addresses
+    #[arg(long, value_name = "PATH")]
+    pub unix: Option<String>,
+}
+
+/// Server settings read from the .env file at the root of the server folder.
+/// Missing keys keep their default value, see README for the list of keys.
+#[derive(Clone, Debug)]
+pub struct Config {
+    // Mail server
+    pub smtp_user: String,
+    pub smtp_pass: String,
+    pub smtp_serv: String,
+    pub mail_from: String,
+
+    // Network
+    pub listen: Vec<String>,
+    pub unix_socket: Option<String>,
+    pub unix_socket_mode: u32,
+
+    // Transport security
+    pub tls_cert: Option<String>,
+    pub tls_key: Option<String>,
+    pub insecure_plaintext: bool,
+    pub max_frame_size: u32,
+
+    // Timeouts
+    pub read_timeout: Duration,
+    pub write_timeout: Duration,
+    pub flow_timeout: Duration,
+    pub idle_timeout: Duration,
+    pub shutdown_grace: Duration,
+    pub session_lifetime: Duration,
+    pub resume_window: Duration,
+
+    // Email tokens of the registrations and password resets
+    pub verification_lifetime: Duration,
+    pub verification_max_attempts: u32,
+
+    // Lockout after failed logins
+    pub lockout_threshold: u32,
+    pub lockout_delay: Duration,
+    pub lockout_max_delay: Duration,
+    pub lockout_reset: Duration,
+
+    // Keys encrypting the verifiers, the `SERVER_PEPPER` environment variable takes precedence
+    pub pepper_file: Option<String>,
+
+    // Argon2id parameters of the new verifiers, the accounts with other ones are rehashed at login
+    pub password_params: Argon2Params,
+
+    // YubiKey challenges, the clients check the server ID before signing
+    pub server_id: String,
+    pub challenge_lifetime: Duration,
+
+    // PIV attestation of the YubiKeys, the file has the Yubico root and intermediate certificates
+    pub attestation_roots: Option<String>,
+    pub require_attestation: bool,
+
+    // TOTP second factor
+    pub totp_issuer: String,
+    pub totp_skew: u64,
+
+    // Rate limiting by source address, `None` is unlimited
+    pub rate_limit_connect: Option<RateLimit>,
+    pub rate_limit_login: Option<RateLimit>,
+    pub rate_limit_register: Option<RateLimit>,
+    pub rate_limit_reset: Option<RateLimit>,
+    pub rate_limit_verify: Option<RateLimit>,
+    pub rate_limit_ipv4_prefix: u32,
+    pub rate_limit_ipv6_prefix: u32,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            smtp_user: String::new(),
+            smtp_pass: String::new(),
+            smtp_serv: String::new(),
+            mail_from: String::new(),
+            listen: vec![String::from("127.0.0.1:8080")],
+            unix_socket: None,
+            unix_socket_mode: 0o660,
+            tls_cert: None,
+            tls_key: None,
+            insecure_plaintext: false,
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            read_timeout: Duration::from_secs(120),
+            write_timeout: Duration::from_secs(30),
+            flow_timeout: Duration::from_secs(600),
+            idle_timeout: Duration::from_secs(600),
+            shutdown_grace: Duration::from_secs(30),
+            session_lifetime: Duration::from_secs(3600),
+            resume_window: Duration::from_secs(300),
+            verification_lifetime: Duration::from_secs(900),
+            verification_max_attempts: 5,
+            lockout_threshold: 5,
+            lockout_delay: Duration::from_secs(30),
+            lockout_max_delay: Duration::from_secs(3600),
+            lockout_reset: Duration::from_secs(86400),
+            pepper_file: None,
+            // OWASP recommendation for Argon2id
+            password_params: Argon2Params::new(19456, 2, 1).unwrap(),
+            server_id: String::from("localhost"),
+            challenge_lifetime: Duration::from_secs(300),
+            attestation_roots: None,
+            require_attestation: false,
+            totp_issuer: String::from("SEC Labo 2"),
+            totp_skew: 1,
+            rate_limit_connect: Some(RateLimit { capacity: 30, period: Duration::from_secs(60) }),
+            rate_limit_login: Some(RateLimit { capacity: 10, period: Duration::from_secs(60) }),
+            rate_limit_register: Some(RateLimit { capacity: 3, period: Duration::from_secs(3600) }),
+            rate_limit_reset: Some(RateLimit { capacity: 3, period: Duration::from_secs(3600) }),
+            rate_limit_verify: Some(RateLimit { capacity: 10, period: Duration::from_secs(3600) }),
+            rate_limit_ipv4_prefix: 32,
+            rate_limit_ipv6_prefix: 64,
+        }
+    }
+}
+
+impl Config {
+    /// Load the settings for the given command line, must be called before any use of `CONFIG`
+    pub fn init(args: Args) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let mut config = match &args.config {
+            Some(path) if !Path::new(path).exists() => return Err(format!("No config file {}", path).into()),
+            Some(path) => Config::load(path),
+            None => Config::load(DEFAULT_CONFIG_PATH),
+        };
+
+        if !args.listen.is_empty() {
+            config.listen = args.listen;
+        }
+        if args.unix.is_some() {
+            config.unix_socket = args.unix;
+        }
+        if config.listen.is_empty() && config.unix_socket.is_none() {
+            return Err("At least one listen address or Unix socket is needed".into());
+        }
+
+        Ok(CONFIG.set(config)?)
+    }
+
+    pub fn load(path: &str) -> Config {
+        let mut config = Config::default();
+
+        // Without a file we keep the defaults, the missing values are reported when used
+        let envfile = match EnvFile::new(Path::new(path)) {
+            Ok(envfile) => envfile,
+            Err(_) => return config,
+        };
+
+        let mut argon2 = config.password_params;
+        for (key, value) in envfile.store {
+            match &*key {
+                "SMTP_USER" => config.smtp_user = value,
+                "SMTP_PASS" => config.smtp_pass = value,
+                "SMTP_SERV" => config.smtp_serv = value,
+                "MAIL_FROM" => config.mail_from = value,
+                "LISTEN" => config.listen = parse_list(&value),
+                "UNIX_SOCKET" => config.unix_socket = Some(value),
+                "UNIX_SOCKET_MODE" => config.unix_socket_mode = parse_octal(&key, &value, config.unix_socket_mode),
+                "TLS_CERT" => config.tls_cert = Some(value),
+                "TLS_KEY" => config.tls_key = Some(value),
+                "INSECURE_PLAINTEXT" => config.insecure_plaintext = parse_bool(&value),
+                "MAX_FRAME_SIZE" => config.max_frame_size = parse_number(&key, &value, config.max_frame_size),
+                "READ_TIMEOUT" => config.read_timeout = parse_seconds(&key, &value, config.read_timeout),
+                "WRITE_TIMEOUT" => config.write_timeout = parse_seconds(&key, &value, config.write_timeout),
+                "FLOW_TIMEOUT" => config.flow_timeout = parse_seconds(&key, &value, config.flow_timeout),
+                "IDLE_TIMEOUT" => config.idle_timeout = parse_seconds(&key, &value, config.idle_timeout),
+                "SHUTDOWN_GRACE" => config.shutdown_grace = parse_seconds(&key, &value, config.shutdown_grace),
+                "SESSION_LIFETIME" => config.session_lifetime = parse_seconds(&key, &value, config.session_lifetime),
+                "RESUME_WINDOW" => config.resume_window = parse_seconds(&key, &value, config.resume_window),
+                "VERIFICATION_LIFETIME" => config.verification_lifetime = parse_seconds(&key, &value, config.verification_lifetime),
+                "VERIFICATION_MAX_ATTEMPTS" => config.verification_max_attempts = parse_number(&key, &value, config.verification_max_attempts),
+                "LOCKOUT_THRESHOLD" => config.lockout_threshold = parse_number(&key, &value, config.lockout_threshold),
+                "LOCKOUT_DELAY" => config.lockout_delay = parse_seconds(&key, &value, config.lockout_delay),
+                "LOCKOUT_MAX_DELAY" => config.lockout_max_delay = parse_seconds(&key, &value, config.lockout_max_delay),
+                "LOCKOUT_RESET" => config.lockout_reset = parse_seconds(&key, &value, config.lockout_reset),
+                "PEPPER_FILE" => config.pepper_file = Some(value),
+                "ARGON2_MEMORY" => argon2.memory = parse_number(&key, &value, argon2.memory),
+                "ARGON2_ITERATIONS" => argon2.iterations = parse_number(&key, &value, argon2.iterations),
+                "ARGON2_PARALLELISM" => argon2.parallelism = parse_number(&key, &value, argon2.parallelism),
+                "SERVER_ID" => config.server_id = value,
+                "CHALLENGE_LIFETIME" => config.challenge_lifetime = parse_seconds(&key, &value, config.challenge_lifetime),
+                "ATTESTATION_ROOTS" => config.attestation_roots = Some(value),
+                "REQUIRE_ATTESTATION" => config.require_attestation = parse_bool(&value),
+                "TOTP_ISSUER" => config.totp_issuer = value,
+                "TOTP_SKEW" => config.totp_skew = parse_number(&key, &value, config.totp_skew),
+                "RATE_LIMIT_CONNECT" => config.rate_limit_connect = parse_rate(&key, &value, config.rate_limit_connect),
+                "RATE_LIMIT_LOGIN" => config.rate_limit_login = parse_rate(&key, &value, config.rate_limit_login),
+                "RATE_LIMIT_REGISTER" => config.rate_limit_register = parse_rate(&key, &value, config.rate_limit_register),
+                "RATE_LIMIT_RESET" => config.rate_limit_reset = parse_rate(&key, &value, config.rate_limit_reset),
+                "RATE_LIMIT_VERIFY" => config.rate_limit_verify = parse_rate(&key, &value, config.rate_limit_verify),
+                "RATE_LIMIT_IPV4_PREFIX" => config.rate_limit_ipv4_prefix = parse_number(&key, &value, config.rate_limit_ipv4_prefix),
+                "RATE_LIMIT_IPV6_PREFIX" => config.rate_limit_ipv6_prefix = parse_number(&key, &value, config.rate_limit_ipv6_prefix),
+                _ => {}
+            }
+        }
+
+        match Argon2Params::new(argon2.memory, argon2.iterations, argon2.parallelism) {
+            Ok(params) => config.password_params = params,
+            Err(error) => println!("{}, the default Argon2 parameters are used", error),
+        }
+
+        config
+    }
+}
+
+fn parse_list(value: &str) -> Vec<String> {
+    value.split(',')
+        .map(|item| item.trim().to_string())
+        .filter(|item| !item.is_empty())
+        .collect()
+}
+
+fn parse_octal(key: &str, value: &str, default: u32) -> u32 {
+    match u32::from_str_radix(value.trim_start_matches("0o"), 8) {
+        Ok(number) => number,
+        Err(_) => {
+            println!("Invalid value for {}, the default one is used", key);
+            default
+        }
+    }
+}
+
+/// `<requests>/<seconds>`, e.g. `10/60`, or `off`
+fn parse_rate(key: &str, value: &str, default: Option<RateLimit>) -> Option<RateLimit> {
+    if value.eq_ignore_ascii_case("off") {
+        return None;
+    }
+    let rate = value.split_once('/').and_then(|(capacity, period)| {
+        Some(RateLimit {
+            capacity: capacity.trim().parse().ok().filter(|capacity| *capacity > 0)?,
+            period: Duration::from_secs(period.trim().parse().ok()?),
+        })
+    });
+    match rate {
+        Some(rate) => Some(rate),
+        None => {
+            println!("Invalid value for {}, the default one is used", key);
+            default
+        }
+    }
+}
+
+fn parse_seconds(key: &str, value: &str, default: Duration) -> Duration {
+    Duration::from_secs(parse_number(key, value, default.as_secs()))
+}
@@ -1,20 +1,102 @@
-use std::net::{TcpStream};
-use serde::Serialize;
-use serde::de::DeserializeOwned;
 use std::error::Error;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time::timeout;
+use app_tools::communication::data::{HelloData, Message, UnexpectedMessage};
+use app_tools::communication::framing::{decode_header, decode_payload, encode, HEADER_SIZE};
+use app_tools::communication::messages::INCOMPATIBLE_VERSION;
+use app_tools::security::crypto::generate_random_16_bytes;
+use crate::config::CONFIG;
+use crate::shutdown;
 
-pub struct Connection {
-    stream: TcpStream
+/// Any stream a client can be served on (TCP, TLS over TCP, Unix socket)
+pub trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
+/// By default the stream type is erased, so the rest of the server
+/// doesn't depend on the transport used by each client
+pub struct Connection<S: Stream = Box<dyn Stream>> {
+    /// Random ID, the challenges signed during a login or a reset are bound to it
+    pub id: [u8; 16],
+    stream: S,
+    max_frame_size: u32,
+    read_timeout: Duration,
+    write_timeout: Duration,
 }
 
-impl Connection {
-    pub fn new(stream: TcpStream) -> Connection { Connection{stream} }
+impl<S: Stream> Connection<S> {
+    pub async fn new(stream: S) -> Result<Connection<S>, Box<dyn Error + Send + Sync>> {
+        let mut id = [0; 16];
+        generate_random_16_bytes(&mut id);
+        let mut connection = Connection{
+            id,
+            stream,
+            max_frame_size: CONFIG.max_frame_size,
+            read_timeout: CONFIG.read_timeout,
+            write_timeout: CONFIG.write_timeout,
+        };
+        connection.handshake().await?;
+        Ok(connection)
+    }
+
+    /// The client speaks first, we always answer with our version so it can report the mismatch
+    async fn handshake(&mut self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let hello: HelloData = self.receive().await?;
+        self.send(HelloData::new()).await?;
+        if !hello.is_compatible() {
+            return Err(format!("{}: client v{}", INCOMPATIBLE_VERSION, hello.version).into());
+        }
+        Ok(())
+    }
+
+    /// A timed out write leaves a partial frame, the connection must be closed after it
+    pub async fn send<T>(&mut self, o: T) -> Result<(), Box<dyn Error + Send + Sync>> where T: Into<Message> {
+        let frame = encode(&o.into(), self.max_frame_size)?;
+        let stream = &mut self.stream;
+        match timeout(self.write_timeout, async {
+            stream.write_all(&frame).await?;
+            stream.flush().await
+        }).await {
+            Ok(result) => Ok(result?),
+            Err(_) => Err("Write timed out".into()),
+        }
+    }
 
-    pub fn send<T>(&mut self, o: &T) -> Result<(), Box<dyn Error>> where T: Serialize {
-        Ok(bincode::serialize_into(&self.stream, &o)?)
+    pub async fn receive<T>(&mut self) -> Result<T, Box<dyn Error + Send + Sync>> where T: TryFrom<Message, Error = UnexpectedMessage> {
+        self.receive_within(self.read_timeout).await
     }
 
-    pub fn receive<T>(&mut self) -> Result<T, Box<dyn Error>> where T: DeserializeOwned {
-        Ok(bincode::deserialize_from(&self.stream)?)
+    /// Wait for the next request of the client, the wait is limited by the idle timeout
+    /// and stops as soon as the server shuts down
+    pub async fn receive_request<T>(&mut self) -> Result<T, Box<dyn Error + Send + Sync>>
+        where T: TryFrom<Message, Error = UnexpectedMessage> {
+        if shutdown::is_triggered() {
+            return Err("Server is shutting down".into());
+        }
+        tokio::select! {
+            request = self.receive_within(CONFIG.idle_timeout) => request,
+            _ = shutdown::triggered() => Err("Server is shutting down".into()),
+        }
     }
-}
\ No newline at end of file
+
+    /// Same as `receive` with another delay than the read timeout,
+    /// a timed out read leaves a partial frame, the connection must be closed after it
+    pub async fn receive_within<T>(&mut self, delay: Duration) -> Result<T, Box<dyn Error + Send + Sync>>
+        where T: TryFrom<Message, Error = UnexpectedMessage> {
+        let max_frame_size = self.max_frame_size;
+        let stream = &mut self.stream;
+        let payload = match timeout(delay, async {
+            let mut header = [0; HEADER_SIZE];
+            stream.read_exact(&mut header).await?;
+            let mut payload = vec![0; decode_header(header, max_frame_size)?];
+            stream.read_exact(&mut payload).await?;
+            Ok::<_, Box<dyn Error + Send + Sync>>(payload)
+        }).await {
+            Ok(payload) => payload?,
+            Err(_) => return Err("Read timed out".into()),
+        };
+        let message: Message = decode_payload(&payload, max_frame_size)?;
+        Ok(T::try_from(message)?)
+    }
+}
@@ -2,7 +2,12 @@ use rustbreak::{FileDatabase, deser::Ron};
 use std::collections::HashMap;
 use std::error::Error;
 use serde::{Serialize, Deserialize};
-use crate::authentication::User;
+use tokio::task;
+use app_tools::communication::data::VerificationPurpose;
+use app_tools::security::crypto::SecretBytes;
+use app_tools::security::srp;
+use crate::authentication::{User, YubiKeyFactor};
+use crate::verification::{self, PendingVerification};
 
 lazy_static! {
     static ref DB: FileDatabase<Database, Ron> = FileDatabase::load_from_path_or_default("db.ron").unwrap();
@@ -10,16 +15,141 @@ lazy_static! {
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Database {
-    data: HashMap<String, User>
+    data: HashMap<String, User>,
+    /// Email tokens not confirmed yet, at most one per email and purpose
+    #[serde(default)]
+    pending: Vec<PendingVerification>,
+    /// Key of the salts given to the emails without account, kept so they don't change with a restart
+    #[serde(default)]
+    salt_key: SecretBytes,
 }
 
+/// Saving writes the whole file, so the methods that save run on the blocking pool and not on the session task
 impl Database {
-    pub fn insert(user: &User) -> Result<(), Box<dyn Error>> {
-        DB.write(|db| db.data.insert(user.email.clone(), user.clone()))?;
-        Ok(DB.save()?)
+    pub async fn insert(user: &User) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let user = user.clone();
+        task::spawn_blocking(move || {
+            DB.write(|db| db.data.insert(user.email.clone(), user))?;
+            DB.save()
+        }).await??;
+        Ok(())
     }
 
-    pub fn get(email: &str) -> Result<Option<User>, Box<dyn Error>> {
+    /// Change the saved account in place and save it. Flows last, so a copy of the account read at their start
+    /// and inserted back would undo what other connections changed in the meantime.
+    /// # Returns
+    /// * `Option<T>` - What `change` returns, `None` if there is no account with this email
+    pub async fn update<T, F>(email: &str, change: F) -> Result<Option<T>, Box<dyn Error + Send + Sync>>
+        where T: Send + 'static,
+              F: FnOnce(&mut User) -> T + Send + 'static {
+        let email = email.to_string();
+        let result = task::spawn_blocking(move || {
+            let result = DB.write(|db| db.data.get_mut(&email).map(change))?;
+            if result.is_some() {
+                DB.save()?;
+            }
+            Ok::<_, rustbreak::RustbreakError>(result)
+        }).await??;
+        Ok(result)
+    }
+
+    /// Save the verification, it replaces the previous one of the email for the same purpose
+    pub async fn insert_pending(pending: PendingVerification) -> Result<(), Box<dyn Error + Send + Sync>> {
+        task::spawn_blocking(move || {
+            DB.write(|db| {
+                db.pending.retain(|other| !other.is_expired() && !other.is_for(pending.purpose, &pending.email));
+                db.pending.push(pending);
+            })?;
+            DB.save()
+        }).await??;
+        Ok(())
+    }
+
+    /// Check the token of the pending verification, it is removed once confirmed,
+    /// expired or after too many wrong tokens
+    /// # Returns
+    /// * `Option<PendingVerification>` - The confirmed verification, `None` if the token is wrong
+    ///   or if there is no verification to confirm
+    pub async fn consume_pending(purpose: VerificationPurpose,
+                                 email: &str,
+                                 token: &str) -> Result<Option<PendingVerification>, Box<dyn Error + Send + Sync>> {
+        let email = email.to_string();
+        let token = token.to_string();
+        let confirmed = task::spawn_blocking(move || {
+            let (confirmed, changed) = DB.write(|db| verification::consume(&mut db.pending, purpose, &email, &token))?;
+            if changed {
+                DB.save()?;
+            }
+            Ok::<_, rustbreak::RustbreakError>(confirmed)
+        }).await??;
+        Ok(confirmed)
+    }
+
+    /// Write the whole database to the file
+    pub async fn save() -> Result<(), Box<dyn Error + Send + Sync>> {
+        task::spawn_blocking(|| DB.save()).await??;
+        Ok(())
+    }
+
+    /// Update the accounts saved by older versions of the server:
+    /// -   The password hashes of the accounts created before SRP are replaced by verifiers.
+    ///     The hash is the SRP password of the client, so the verifier is derived from it
+    ///     and the users keep their password.
+    /// -   The single YubiKey of the accounts becomes the first key of their list
+    ///
+    /// The key of the salts of the emails without account is drawn on the first start.
+    /// # Returns
+    /// * `usize` - The number of migrated accounts
+    pub async fn migrate() -> Result<usize, Box<dyn Error + Send + Sync>> {
+        let migrated = task::spawn_blocking(|| {
+            let (migrated, new_key) = DB.write(|db| {
+                let new_key = db.salt_key.is_empty();
+                if new_key {
+                    db.salt_key = SecretBytes::random(32);
+                }
+
+                let mut migrated = 0;
+                for user in db.data.values_mut() {
+                    let mut changed = false;
+                    if !user.hash_password.is_empty() {
+                        let verifier = srp::compute_verifier(&user.email, &user.hash_password, &user.salt);
+                        user.set_verifier(&verifier);
+                        user.hash_password.clear();
+                        changed = true;
+                    }
+                    if !user.public_yubikey.is_empty() {
+                        let public_key = std::mem::take(&mut user.public_yubikey);
+                        user.yubikeys.push(YubiKeyFactor::new(String::from("YubiKey"), public_key, None));
+                        changed = true;
+                    }
+                    if changed {
+                        migrated += 1;
+                    }
+                }
+                (migrated, new_key)
+            })?;
+            if migrated > 0 || new_key {
+                DB.save()?;
+            }
+            Ok::<_, rustbreak::RustbreakError>(migrated)
+        }).await??;
+        Ok(migrated)
+    }
+
+    pub fn salt_key() -> Result<SecretBytes, Box<dyn Error + Send + Sync>> {
+        Ok(DB.borrow_data()?.salt_key.clone())
+    }
+
+    /// Number of accounts of each Argon2 parameters, the emails without account get them in the same proportions
+    pub fn password_params_in_use() -> Result<Vec<(String, usize)>, Box<dyn Error + Send + Sync>> {
+        let mut in_use: HashMap<String, usize> = HashMap::new();
+        for user in DB.borrow_data()?.data.values() {
+            *in_use.entry(user.password_params.clone()).or_default() += 1;
+        }
+        Ok(in_use.into_iter().collect())
+    }
+
+    pub fn get(email: &str) -> Result<Option<User>, Box<dyn Error + Send + Sync>> {
         Ok(match DB.borrow_data()?.data.get(email) {
             Some(user) => Some(user.clone()),
             None => None
@@ -29,6 +159,6 @@ impl Database {
 
 impl Default for Database {
     fn default() -> Self {
-        Database{data: HashMap::new()}
+        Database{data: HashMap::new(), pending: Vec::new(), salt_key: SecretBytes::default()}
     }
-}
\ No newline at end of file
+}
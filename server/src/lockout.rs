@@ -0,0 +1,175 @@
+use serde::{Serialize, Deserialize};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use crate::authentication_tools::now;
+use crate::config::{Config, CONFIG};
+
+/// Emails without an account tracked at most, beyond that the oldest ones are forgotten
+const MAX_UNKNOWN: usize = 10_000;
+
+lazy_static! {
+    /// Failures of the emails without an account, kept in memory only
+    static ref UNKNOWN: Mutex<HashMap<String, LoginFailures>> = Mutex::new(HashMap::new());
+}
+
+/// Failed logins of an account, times are UNIX timestamps in seconds so they survive a restart
+#[derive(Serialize, Deserialize, Clone, Default, Debug)]
+pub struct LoginFailures {
+    pub count: u32,
+    pub last_failure: Option<u64>,
+    pub locked_until: Option<u64>,
+}
+
+impl LoginFailures {
+    pub fn is_locked(&self) -> bool {
+        self.is_locked_at(now())
+    }
+
+    /// From `LOCKOUT_THRESHOLD` failures the account is locked, the delay doubles with each
+    /// new failure up to `LOCKOUT_MAX_DELAY`. Failures older than `LOCKOUT_RESET` are forgotten.
+    pub fn record_failure(&mut self) {
+        self.record_failure_at(now(), &CONFIG);
+    }
+
+    pub fn record_success(&mut self) {
+        *self = LoginFailures::default();
+    }
+
+    fn is_locked_at(&self, now: u64) -> bool {
+        self.locked_until.is_some_and(|until| until > now)
+    }
+
+    /// Not locked and without failure for `LOCKOUT_RESET`, the next failure counts from zero
+    fn is_forgotten_at(&self, now: u64, config: &Config) -> bool {
+        !self.is_locked_at(now)
+            && self.last_failure.is_none_or(|last| now.saturating_sub(last) > config.lockout_reset.as_secs())
+    }
+
+    fn record_failure_at(&mut self, now: u64, config: &Config) {
+        if self.last_failure.is_some_and(|last| now.saturating_sub(last) > config.lockout_reset.as_secs()) {
+            self.count = 0;
+        }
+        self.count = self.count.saturating_add(1);
+        self.last_failure = Some(now);
+
+        if self.count >= config.lockout_threshold {
+            let exponent = (self.count - config.lockout_threshold).min(31);
+            let delay = config.lockout_delay.as_secs()
+                .saturating_mul(1 << exponent)
+                .min(config.lockout_max_delay.as_secs());
+            self.locked_until = Some(now + delay);
+        }
+    }
+}
+
+/// Emails without an account are locked exactly like accounts,
+/// so a lock doesn't tell whether the account exists
+pub fn unknown_failures(email: &str) -> LoginFailures {
+    UNKNOWN.lock().unwrap().get(email).cloned().unwrap_or_default()
+}
+
+/// Only valid emails are counted, an account can't have another one
+pub fn record_unknown_failure(email: &str) {
+    record_unknown_failure_in(&mut UNKNOWN.lock().unwrap(), email, now(), &CONFIG);
+}
+
+fn record_unknown_failure_in(unknown: &mut HashMap<String, LoginFailures>, email: &str, now: u64, config: &Config) {
+    if unknown.len() >= MAX_UNKNOWN && !unknown.contains_key(email) {
+        unknown.retain(|_, failures| !failures.is_forgotten_at(now, config));
+        if unknown.len() >= MAX_UNKNOWN {
+            if let Some(oldest) = unknown.iter().min_by_key(|(_, failures)| failures.last_failure).map(|(email, _)| email.clone()) {
+                unknown.remove(&oldest);
+            }
+        }
+    }
+    unknown.entry(email.to_string()).or_default().record_failure_at(now, config);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn config() -> Config {
+        Config {
+            lockout_threshold: 3,
+            lockout_delay: Duration::from_secs(30),
+            lockout_max_delay: Duration::from_secs(100),
+            lockout_reset: Duration::from_secs(1000),
+            ..Config::default()
+        }
+    }
+
+    #[test]
+    fn lockout_back_off() {
+        let config = config();
+        let mut failures = LoginFailures::default();
+
+        // Pass
+        failures.record_failure_at(0, &config);
+        failures.record_failure_at(1, &config);
+        assert!(!failures.is_locked_at(1));
+
+        // Fail, the delay doubles up to the maximum
+        failures.record_failure_at(2, &config);
+        assert_eq!(failures.locked_until, Some(32));
+        assert!(failures.is_locked_at(31));
+        assert!(!failures.is_locked_at(32));
+        failures.record_failure_at(40, &config);
+        assert_eq!(failures.locked_until, Some(100));
+        failures.record_failure_at(200, &config);
+        assert_eq!(failures.locked_until, Some(300));
+        for now in 300..400 {
+            failures.record_failure_at(now, &config);
+        }
+        assert_eq!(failures.locked_until, Some(499));
+
+        failures.record_success();
+        assert_eq!(failures.count, 0);
+        assert!(!failures.is_locked_at(400));
+    }
+
+    #[test]
+    fn lockout_reset() {
+        let config = config();
+        let mut failures = LoginFailures::default();
+        failures.record_failure_at(0, &config);
+        failures.record_failure_at(1, &config);
+
+        // Pass
+        assert!(!failures.is_forgotten_at(1000, &config));
+        assert!(failures.is_forgotten_at(1002, &config));
+        failures.record_failure_at(1002, &config);
+        assert_eq!(failures.count, 1);
+        assert!(!failures.is_locked_at(1002));
+
+        // Fail, a lock is kept until it ends
+        failures.locked_until = Some(5000);
+        assert!(!failures.is_forgotten_at(4000, &config));
+    }
+
+    #[test]
+    fn unknown_failures_bounded() {
+        let config = Config {
+            lockout_reset: Duration::from_secs(100_000),
+            ..config()
+        };
+        let mut unknown = HashMap::new();
+        for i in 0..MAX_UNKNOWN {
+            record_unknown_failure_in(&mut unknown, &format!("user{}@example.com", i), i as u64, &config);
+        }
+        assert_eq!(unknown.len(), MAX_UNKNOWN);
+
+        // The oldest email is dropped for a new one
+        record_unknown_failure_in(&mut unknown, "new@example.com", MAX_UNKNOWN as u64, &config);
+        assert_eq!(unknown.len(), MAX_UNKNOWN);
+        assert!(!unknown.contains_key("user0@example.com"));
+        assert!(unknown.contains_key("user1@example.com"));
+
+        // The forgotten ones are all dropped first
+        record_unknown_failure_in(&mut unknown, "late@example.com", 105_000, &config);
+        assert!(unknown.len() < MAX_UNKNOWN);
+        assert!(unknown.contains_key("user9999@example.com"));
+        assert!(!unknown.contains_key("user5@example.com"));
+    }
+}
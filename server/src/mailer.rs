@@ -1,60 +1,50 @@
-extern crate envfile;
-
 use lettre::transport::smtp::authentication::Credentials;
-use lettre::{Message, SmtpTransport, Transport};
-use envfile::EnvFile;
-use std::path::Path;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
 use std::error::Error;
 
-// To use email, create a .env file at the root and add these values to it:
+use crate::config::CONFIG;
+use crate::shutdown;
+
+// To use email, add these values to the .env file at the root:
 //SMTP_USER=x
 //SMTP_PASS=x
 //SMTP_SERV=x
 //MAIL_FROM=x
-fn read_env_file() -> Result<(String, String, String, String), Box<dyn Error>> {
-    let envfile = EnvFile::new(&Path::new("./.env"))?;
-
-    let mut smtp_user= String::from("");
-    let mut smtp_pass= String::from("");
-    let mut smtp_serv= String::from("");
-    let mut mail_from= String::from("");
-
-    for (key, value) in envfile.store {
-        match &*key {
-            "SMTP_USER" => smtp_user = format!("{}", value),
-            "SMTP_PASS" => smtp_pass = format!("{}", value),
-            "SMTP_SERV" => smtp_serv = format!("{}", value),
-            "MAIL_FROM" => mail_from = format!("{}", value),
-            _ => {}
-        }
-    }
-
-    if smtp_user == "" || smtp_pass == "" || smtp_serv == "" || mail_from == "" {
+fn read_mail_config() -> Result<(String, String, String, String), Box<dyn Error + Send + Sync>> {
+    if CONFIG.smtp_user.is_empty() || CONFIG.smtp_pass.is_empty() ||
+        CONFIG.smtp_serv.is_empty() || CONFIG.mail_from.is_empty() {
         Err("INVALID ENV FILE".into())
     } else {
-        Ok((smtp_user, smtp_pass, smtp_serv, mail_from))
+        Ok((CONFIG.smtp_user.clone(), CONFIG.smtp_pass.clone(),
+            CONFIG.smtp_serv.clone(), CONFIG.mail_from.clone()))
     }
 }
 
-pub fn send_mail(dst: &str, subject: &str, message: &str) -> Result<(), Box<dyn Error>> {
-    let (smtp_user, smtp_pass, smtp_serv, mail_from) = read_env_file()?;
+/// Build the mail and send it in background, the session that triggered it doesn't wait
+/// for the SMTP server. Only errors in the mail itself are returned, sending errors are logged.
+pub fn send_mail(dst: &str, subject: &str, message: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let (smtp_user, smtp_pass, smtp_serv, mail_from) = read_mail_config()?;
 
     let email = Message::builder()
-        .from(mail_from.parse().unwrap())
-        .reply_to(mail_from.parse().unwrap())
-        .to(dst.parse().unwrap())
+        .from(mail_from.parse()?)
+        .reply_to(mail_from.parse()?)
+        .to(dst.parse()?)
         .subject(subject.to_string())
-        .body(message.to_string())
-        .unwrap();
-    let creds = Credentials::new(smtp_user.to_string(), smtp_pass.to_string());
+        .body(message.to_string())?;
+    let creds = Credentials::new(smtp_user, smtp_pass);
 
-    let mailer = SmtpTransport::relay(&smtp_serv)
-        .unwrap()
+    let mailer = AsyncSmtpTransport::<Tokio1Executor>::relay(&smtp_serv)?
         .credentials(creds)
         .build();
 
-    match mailer.send(&email) {
-        Ok(_) => Ok(()),
-        Err(e) => Err(format!("Could not send email: {:?}", e).into()),
-    }
-}
\ No newline at end of file
+    // Tracked so a shutdown waits for the mail to be sent
+    let guard = shutdown::track();
+    tokio::spawn(async move {
+        let _guard = guard;
+        if let Err(e) = mailer.send(email).await {
+            println!("Could not send email: {:?}", e);
+        }
+    });
+
+    Ok(())
+}
@@ -4,20 +4,89 @@ mod database;
 mod action;
 mod mailer;
 mod authentication_tools;
+mod config;
+mod tls;
+mod shutdown;
+mod session;
+mod lockout;
+mod rate_limit;
+mod pepper;
+mod verification;
+mod attestation;
 
 #[macro_use]
 extern crate lazy_static;
 
-use std::net::TcpListener;
-use std::thread;
+use std::error::Error;
+use std::net::IpAddr;
+use std::process::ExitCode;
+use clap::Parser;
+use tokio::net::TcpListener;
+#[cfg(unix)]
+use tokio::net::UnixListener;
+use tokio::task::JoinSet;
+use tokio::time::timeout;
+use tokio_rustls::TlsAcceptor;
+use app_tools::communication::data::{AuthenticateRequest, ServerResponse, SessionData};
+use app_tools::communication::status::StatusCode;
 use crate::action::Action;
-use crate::connection::Connection;
+use crate::connection::{Connection, Stream};
 use crate::authentication::Authenticate;
+use crate::config::{Args, Config, CONFIG};
+use crate::database::Database;
+use crate::rate_limit::Flow;
 
-fn handle_client(mut connection: Connection) {
+async fn handle_client(mut connection: Connection, peer: Option<IpAddr>) {
     loop {
-        match Authenticate::perform(&mut connection) {
-            Ok(Some(mut user)) => while let Ok(true) = Action::perform(&mut user, &mut connection) {},
+        let request = match connection.receive_request().await {
+            Ok(request) => request,
+            Err(error) => {
+                println!("{}", error);
+                return
+            }
+        };
+
+        // Limited before the flow starts, registrations and resets send emails
+        match accept_request(&mut connection, request, peer).await {
+            Ok(true) => {},
+            Ok(false) => continue,
+            Err(error) => {
+                println!("{}", error);
+                return
+            }
+        }
+
+        match Authenticate::perform(request, &mut connection).await {
+            Ok(Some((mut user, session))) => {
+                // The token authorizes the actions, the ticket lets the client come back if the connection drops
+                let ticket = session::issue_ticket(&session);
+                let session_data = SessionData {
+                    token: session.token(),
+                    ticket: ticket.clone(),
+                    recovery_codes: user.recovery_codes.len() as u32,
+                };
+                if let Err(error) = connection.send(session_data).await {
+                    session::revoke(&session);
+                    println!("{}", error);
+                    return
+                }
+
+                loop {
+                    match Action::perform(&mut user, &session, &mut connection).await {
+                        Ok(true) => session::touch(&ticket),
+                        Ok(false) => {
+                            session::revoke(&session);
+                            break
+                        },
+                        Err(error) => {
+                            // The window to resume starts now
+                            session::touch(&ticket);
+                            println!("{}", error);
+                            return
+                        }
+                    }
+                }
+            },
             Err(error) => {
                 println!("{}", error);
                 return
@@ -27,19 +96,196 @@ fn handle_client(mut connection: Connection) {
     }
 }
 
-const SERVER_IP: &str = "127.0.0.1:8080";
+/// Tell the client whether its request is served, each flow is limited by source subnet.
+/// Clients on the Unix socket have no address and aren't limited.
+async fn accept_request(connection: &mut Connection,
+                        request: AuthenticateRequest,
+                        peer: Option<IpAddr>) -> Result<bool, Box<dyn Error + Send + Sync>> {
+    let flow = match request {
+        AuthenticateRequest::Authenticate | AuthenticateRequest::Resume => Flow::Login,
+        AuthenticateRequest::Register => Flow::Register,
+        AuthenticateRequest::Reset => Flow::Reset,
+        AuthenticateRequest::Verify => Flow::Verify,
+        AuthenticateRequest::Exit => return Ok(true),
+    };
 
-fn main() {
-    let listener = TcpListener::bind(SERVER_IP).unwrap();
+    let accepted = peer.is_none_or(|ip| rate_limit::allow(flow, ip));
+    if !accepted {
+        println!("{:?} request from {:?} rate limited", flow, peer);
+    }
+    connection.send(ServerResponse {
+        status: if accepted { StatusCode::Accepted } else { StatusCode::RateLimited },
+    }).await?;
+    Ok(accepted)
+}
 
-    println!("Server is UP.\nServing clients on {}", SERVER_IP);
+/// Each client is a task and not a thread, idle clients only cost their buffers
+fn spawn_session(stream: Box<dyn Stream>, peer: Option<IpAddr>) {
+    let guard = shutdown::track();
+    tokio::spawn(async move {
+        let _guard = guard;
+        let connection = match Connection::new(stream).await {
+            Ok(connection) => connection,
+            Err(e) => return println!("Connection failed with error: {}", e),
+        };
+        handle_client(connection, peer).await;
+    });
+}
 
-    for stream in listener.incoming() {
-        match stream {
-            Ok(stream) => { thread::spawn(move || { handle_client(Connection::new(stream)); }); },
+async fn serve_tcp(listener: TcpListener, tls: Option<TlsAcceptor>) {
+    loop {
+        match listener.accept().await {
+            // Refused before the TLS handshake, so a flood costs as little as possible
+            Ok((_, addr)) if !rate_limit::allow(Flow::Connect, addr.ip()) => {
+                println!("Connection from {} rate limited", addr.ip());
+            },
+            Ok((stream, addr)) => match &tls {
+                Some(tls) => {
+                    let tls = tls.clone();
+                    // The TLS handshake is done in the session task so a slow client doesn't block accept
+                    let guard = shutdown::track();
+                    tokio::spawn(async move {
+                        let _guard = guard;
+                        match tls::accept(&tls, stream).await {
+                            Ok(stream) => spawn_session(Box::new(stream), Some(addr.ip())),
+                            Err(e) => println!("Connection failed with error: {}", e),
+                        }
+                    });
+                },
+                None => spawn_session(Box::new(stream), Some(addr.ip())),
+            },
+            Err(e) => { println!("Connection failed with error: {}", e); }
+        }
+    }
+}
+
+/// Local clients don't need TLS, they are identified by their credentials
+#[cfg(unix)]
+async fn serve_unix(listener: UnixListener) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                match stream.peer_cred() {
+                    Ok(cred) => println!("Unix connection from uid={} gid={} pid={}",
+                                         cred.uid(), cred.gid(),
+                                         cred.pid().map_or(String::from("?"), |pid| pid.to_string())),
+                    Err(e) => println!("Unix connection without peer credentials: {}", e),
+                }
+                spawn_session(Box::new(stream), None);
+            },
             Err(e) => { println!("Connection failed with error: {}", e); }
         }
     }
+}
+
+/// Bind the socket file with the configured permissions, a file left by a previous run is replaced
+#[cfg(unix)]
+fn bind_unix(path: &str) -> std::io::Result<UnixListener> {
+    use std::fs::{self, Permissions};
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+
+    if let Ok(metadata) = fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            return Err(std::io::Error::new(std::io::ErrorKind::AlreadyExists, "not a socket"));
+        }
+        fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    fs::set_permissions(path, Permissions::from_mode(CONFIG.unix_socket_mode))?;
+    Ok(listener)
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    if let Err(e) = Config::init(Args::parse()) {
+        eprintln!("Invalid configuration: {}", e);
+        return ExitCode::FAILURE;
+    }
+
+    let tls = match tls::server_config() {
+        Ok(tls) => tls.map(TlsAcceptor::from),
+        Err(e) => {
+            eprintln!("Could not setup TLS: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    match pepper::load() {
+        Ok(Some(key_id)) => println!("Verifiers are wrapped with the pepper key {}", key_id),
+        Ok(None) => println!("WARNING: No pepper, the verifiers are stored as is in the database (PEPPER_FILE)"),
+        Err(e) => {
+            eprintln!("Could not load the pepper: {}", e);
+            return ExitCode::FAILURE;
+        }
+    }
+
+    match attestation::load() {
+        Ok(0) => println!("WARNING: YubiKey attestations aren't checked, any P-256 key can be registered (ATTESTATION_ROOTS)"),
+        Ok(_) if CONFIG.require_attestation => println!("Only attested YubiKeys can be registered"),
+        Ok(count) => println!("YubiKey attestations are checked against {} certificate(s)", count),
+        Err(e) => {
+            eprintln!("Could not load the attestation roots: {}", e);
+            return ExitCode::FAILURE;
+        }
+    }
+
+    match Database::migrate().await {
+        Ok(0) => {},
+        Ok(migrated) => println!("{} account(s) migrated", migrated),
+        Err(e) => {
+            eprintln!("Could not migrate the database: {}", e);
+            return ExitCode::FAILURE;
+        }
+    }
+
+    let mut servers = JoinSet::new();
+    for addr in &CONFIG.listen {
+        match TcpListener::bind(addr).await {
+            Ok(listener) => { servers.spawn(serve_tcp(listener, tls.clone())); },
+            Err(e) => {
+                eprintln!("Could not listen on {}: {}", addr, e);
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+    let mut addresses = CONFIG.listen.clone();
+
+    #[cfg(unix)]
+    if let Some(path) = &CONFIG.unix_socket {
+        match bind_unix(path) {
+            Ok(listener) => { servers.spawn(serve_unix(listener)); },
+            Err(e) => {
+                eprintln!("Could not listen on {}: {}", path, e);
+                return ExitCode::FAILURE;
+            }
+        }
+        addresses.push(format!("unix:{}", path));
+    }
+
+    println!("Server is UP.\nServing clients on {}", addresses.join(", "));
+
+    shutdown::wait_signal().await;
+
+    // Stop accepting, the clients in the middle of a flow can finish it,
+    // the idle ones are disconnected
+    servers.abort_all();
+    println!("Server is shutting down, waiting at most {}s for clients...", CONFIG.shutdown_grace.as_secs());
+    let mut status = ExitCode::SUCCESS;
+    if timeout(CONFIG.shutdown_grace, shutdown::tasks_done()).await.is_err() {
+        println!("Some clients didn't finish in time");
+        status = ExitCode::FAILURE;
+    }
+
+    if let Err(e) = Database::save().await {
+        println!("Could not save the database: {}", e);
+        status = ExitCode::FAILURE;
+    }
+
+    #[cfg(unix)]
+    if let Some(path) = &CONFIG.unix_socket {
+        let _ = std::fs::remove_file(path);
+    }
 
     println!("Server DOWN.");
-}
\ No newline at end of file
+    status
+}
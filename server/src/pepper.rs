@@ -0,0 +1,205 @@
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use std::error::Error;
+use std::fs;
+use std::sync::OnceLock;
+use app_tools::security::crypto::generate_random_bytes;
+use crate::config::CONFIG;
+
+/// Environment variable with the keys, it takes precedence over `PEPPER_FILE`
+const PEPPER_VAR: &str = "SERVER_PEPPER";
+const KEY_SIZE: usize = 32;
+const NONCE_SIZE: usize = 12;
+
+static KEYS: OnceLock<Vec<PepperKey>> = OnceLock::new();
+
+/// Secret of the server that never goes in the database, the verifiers are encrypted with it
+struct PepperKey {
+    id: u32,
+    key: [u8; KEY_SIZE],
+}
+
+/// Load the keys, written `<id>:<64 hex characters>`, one per line in `PEPPER_FILE`
+/// or separated by commas in `SERVER_PEPPER`. The last one wraps the verifiers, the others are kept
+/// to unwrap the accounts not logged in since the rotation.
+/// # Returns
+/// * `Option<u32>` - The ID of the current key, `None` without pepper
+pub fn load() -> Result<Option<u32>, Box<dyn Error + Send + Sync>> {
+    let keys = match std::env::var(PEPPER_VAR) {
+        Ok(value) => parse_keys(&value.replace(',', "\n"))?,
+        Err(_) => match &CONFIG.pepper_file {
+            Some(path) => {
+                let text = fs::read_to_string(path).map_err(|e| format!("Could not read {}: {}", path, e))?;
+                parse_keys(&text)?
+            },
+            None => vec![],
+        },
+    };
+
+    let current = keys.last().map(|key| key.id);
+    KEYS.set(keys).map_err(|_| "Pepper already loaded")?;
+    Ok(current)
+}
+
+/// Encrypt the verifier with the current key, the email binds it to its account
+/// # Returns
+/// * `(Option<u32>, Vec<u8>)` - The ID of the key and the nonce followed by the ciphertext,
+///   the verifier as is without pepper
+pub fn wrap(email: &str, verifier: &[u8]) -> (Option<u32>, Vec<u8>) {
+    wrap_with(keys(), email, verifier)
+}
+
+/// # Errors
+/// * `String` - The key isn't loaded anymore, or the verifier has been altered or moved to another account
+pub fn unwrap(email: &str, key_id: Option<u32>, wrapped: &[u8]) -> Result<Vec<u8>, String> {
+    unwrap_with(keys(), email, key_id, wrapped)
+}
+
+/// Accounts wrapped with an older key, or saved without pepper, are wrapped again at their next login
+pub fn needs_rewrap(key_id: Option<u32>) -> bool {
+    key_id != keys().last().map(|key| key.id)
+}
+
+fn keys() -> &'static [PepperKey] {
+    KEYS.get().map_or(&[], Vec::as_slice)
+}
+
+fn wrap_with(keys: &[PepperKey], email: &str, verifier: &[u8]) -> (Option<u32>, Vec<u8>) {
+    let key = match keys.last() {
+        Some(key) => key,
+        None => return (None, verifier.to_vec()),
+    };
+
+    let mut nonce = [0; NONCE_SIZE];
+    generate_random_bytes(&mut nonce);
+    let cipher = ChaCha20Poly1305::new(Key::from_slice(&key.key));
+    // The encryption only fails for messages of gigabytes
+    let ciphertext = cipher.encrypt(Nonce::from_slice(&nonce), Payload {
+        msg: verifier,
+        aad: &associated_data(key.id, email),
+    }).unwrap();
+
+    let mut wrapped = nonce.to_vec();
+    wrapped.extend(ciphertext);
+    (Some(key.id), wrapped)
+}
+
+fn unwrap_with(keys: &[PepperKey], email: &str, key_id: Option<u32>, wrapped: &[u8]) -> Result<Vec<u8>, String> {
+    let id = match key_id {
+        Some(id) => id,
+        None => return Ok(wrapped.to_vec()),
+    };
+    let key = keys.iter()
+        .find(|key| key.id == id)
+        .ok_or_else(|| format!("Pepper key {} of {} isn't loaded", id, email))?;
+    if wrapped.len() < NONCE_SIZE {
+        return Err(format!("Verifier of {} is too short", email));
+    }
+
+    let (nonce, ciphertext) = wrapped.split_at(NONCE_SIZE);
+    let cipher = ChaCha20Poly1305::new(Key::from_slice(&key.key));
+    cipher.decrypt(Nonce::from_slice(nonce), Payload {
+        msg: ciphertext,
+        aad: &associated_data(id, email),
+    }).map_err(|_| format!("Verifier of {} can't be unwrapped with pepper key {}", email, id))
+}
+
+fn associated_data(id: u32, email: &str) -> Vec<u8> {
+    let mut data = id.to_be_bytes().to_vec();
+    data.extend_from_slice(email.as_bytes());
+    data
+}
+
+fn parse_keys(text: &str) -> Result<Vec<PepperKey>, Box<dyn Error + Send + Sync>> {
+    let mut keys: Vec<PepperKey> = Vec::new();
+    for line in text.lines().map(str::trim).filter(|line| !line.is_empty() && !line.starts_with('#')) {
+        let (id, key) = line.split_once(':').ok_or("Pepper keys are written <id>:<64 hex characters>")?;
+        let id: u32 = id.trim().parse().map_err(|_| format!("Invalid pepper key ID {}", id))?;
+        let key = decode_hex(key.trim())
+            .and_then(|key| key.try_into().ok())
+            .ok_or_else(|| format!("Pepper key {} must be 64 hex characters", id))?;
+        if keys.iter().any(|other| other.id == id) {
+            return Err(format!("Pepper key ID {} is used twice", id).into());
+        }
+        keys.push(PepperKey { id, key });
+    }
+    Ok(keys)
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return None;
+    }
+    (0..hex.len()).step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY_1: &str = "1:000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+    const KEY_2: &str = "2:1f1e1d1c1b1a191817161514131211100f0e0d0c0b0a09080706050403020100";
+
+    fn parsed(text: &str) -> Vec<PepperKey> {
+        parse_keys(text).unwrap()
+    }
+
+    #[test]
+    fn wrap_and_unwrap() {
+        let keys = parsed(&format!("{}\n{}", KEY_1, KEY_2));
+        let (id, wrapped) = wrap_with(&keys, "email@example.com", b"verifier");
+
+        // Pass
+        assert_eq!(id, Some(2));
+        assert_ne!(wrapped, b"verifier");
+        assert_eq!(unwrap_with(&keys, "email@example.com", id, &wrapped).unwrap(), b"verifier");
+        // The old keys still unwrap the accounts not logged in since the rotation
+        let (old_id, old_wrapped) = wrap_with(&keys[..1], "email@example.com", b"verifier");
+        assert_eq!(unwrap_with(&keys, "email@example.com", old_id, &old_wrapped).unwrap(), b"verifier");
+
+        // Fail
+        assert!(unwrap_with(&keys, "other@example.com", id, &wrapped).is_err());
+        assert!(unwrap_with(&keys, "email@example.com", Some(1), &wrapped).is_err());
+        assert!(unwrap_with(&keys[..1], "email@example.com", id, &wrapped).is_err());
+        let mut altered = wrapped.clone();
+        *altered.last_mut().unwrap() ^= 1;
+        assert!(unwrap_with(&keys, "email@example.com", id, &altered).is_err());
+        assert!(unwrap_with(&keys, "email@example.com", id, &wrapped[..NONCE_SIZE - 1]).is_err());
+    }
+
+    #[test]
+    fn wrap_with_wrong_key() {
+        let wrapped = wrap_with(&parsed(KEY_1), "email@example.com", b"verifier");
+        let other = parsed("1:ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff");
+
+        assert!(unwrap_with(&other, "email@example.com", wrapped.0, &wrapped.1).is_err());
+    }
+
+    #[test]
+    fn wrap_without_pepper() {
+        let (id, wrapped) = wrap_with(&[], "email@example.com", b"verifier");
+
+        assert_eq!(id, None);
+        assert_eq!(wrapped, b"verifier");
+        assert_eq!(unwrap_with(&[], "email@example.com", None, &wrapped).unwrap(), b"verifier");
+    }
+
+    #[test]
+    fn parse_pepper_keys() {
+        // Pass
+        assert_eq!(parsed("").len(), 0);
+        assert_eq!(parsed(&format!("# Rotated on 2024-01-01\n\n {} \n{}\n", KEY_1, KEY_2)).len(), 2);
+        assert_eq!(parsed(KEY_1)[0].id, 1);
+        assert_eq!(parsed(KEY_1)[0].key[31], 0x1f);
+
+        // Fail
+        assert!(parse_keys("000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f").is_err());
+        assert!(parse_keys("x:000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f").is_err());
+        assert!(parse_keys("1:000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e").is_err());
+        assert!(parse_keys("1:000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f00").is_err());
+        assert!(parse_keys("1:zz0102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f").is_err());
+        assert!(parse_keys(&format!("{}\n{}", KEY_1, KEY_1)).is_err());
+    }
+}
\ No newline at end of file
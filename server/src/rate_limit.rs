@@ -0,0 +1,162 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::time::Instant;
+use crate::config::CONFIG;
+
+/// Buckets tracked at most, beyond that the full ones are forgotten
+const MAX_BUCKETS: usize = 100_000;
+
+lazy_static! {
+    static ref BUCKETS: Mutex<HashMap<(Flow, IpAddr), Bucket>> = Mutex::new(HashMap::new());
+}
+
+/// What a client is limited on, each one has its own bucket
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum Flow {
+    Connect,
+    Login,
+    Register,
+    Reset,
+    Verify,
+}
+
+/// Token bucket policy: `capacity` requests at once, refilled at `capacity` tokens per `period`
+#[derive(Clone, Copy, Debug)]
+pub struct RateLimit {
+    pub capacity: u32,
+    pub period: Duration,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self, limit: &RateLimit, now: Instant) {
+        let rate = limit.capacity as f64 / limit.period.as_secs_f64().max(1.0);
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(limit.capacity as f64);
+        self.updated = now;
+    }
+
+    fn take(&mut self, limit: &RateLimit, now: Instant) -> bool {
+        self.refill(limit, now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Take a token for the subnet of the client
+/// # Returns
+/// * `bool` - `false` if the client has to wait before doing this again
+pub fn allow(flow: Flow, ip: IpAddr) -> bool {
+    let limit = match limit_of(flow) {
+        Some(limit) => limit,
+        None => return true,
+    };
+
+    let now = Instant::now();
+    let mut buckets = BUCKETS.lock().unwrap();
+    if buckets.len() >= MAX_BUCKETS {
+        buckets.retain(|(flow, _), bucket| match limit_of(*flow) {
+            Some(limit) => {
+                bucket.refill(&limit, now);
+                bucket.tokens < limit.capacity as f64
+            },
+            None => false,
+        });
+    }
+
+    buckets.entry((flow, subnet(ip, CONFIG.rate_limit_ipv4_prefix, CONFIG.rate_limit_ipv6_prefix)))
+        .or_insert(Bucket {
+            tokens: limit.capacity as f64,
+            updated: now,
+        })
+        .take(&limit, now)
+}
+
+fn limit_of(flow: Flow) -> Option<RateLimit> {
+    match flow {
+        Flow::Connect => CONFIG.rate_limit_connect,
+        Flow::Login => CONFIG.rate_limit_login,
+        Flow::Register => CONFIG.rate_limit_register,
+        Flow::Reset => CONFIG.rate_limit_reset,
+        Flow::Verify => CONFIG.rate_limit_verify,
+    }
+}
+
+/// Clients of a same subnet share their buckets, an IPv6 client usually gets a whole /64
+fn subnet(ip: IpAddr, ipv4_prefix: u32, ipv6_prefix: u32) -> IpAddr {
+    match ip {
+        IpAddr::V4(ip) => {
+            let prefix = ipv4_prefix.min(32);
+            let mask = u32::MAX.checked_shl(32 - prefix).unwrap_or(0);
+            IpAddr::from((u32::from(ip) & mask).to_be_bytes())
+        },
+        IpAddr::V6(ip) => {
+            // An IPv4 client on a dual stack socket is limited as IPv4
+            if let Some(ipv4) = ip.to_ipv4_mapped() {
+                return subnet(IpAddr::V4(ipv4), ipv4_prefix, ipv6_prefix);
+            }
+            let prefix = ipv6_prefix.min(128);
+            let mask = u128::MAX.checked_shl(128 - prefix).unwrap_or(0);
+            IpAddr::from((u128::from(ip) & mask).to_be_bytes())
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIMIT: RateLimit = RateLimit {
+        capacity: 3,
+        period: Duration::from_secs(60),
+    };
+
+    #[test]
+    fn bucket_refill() {
+        let start = Instant::now();
+        let mut bucket = Bucket {
+            tokens: LIMIT.capacity as f64,
+            updated: start,
+        };
+
+        // Pass, the whole capacity at once
+        assert!(bucket.take(&LIMIT, start));
+        assert!(bucket.take(&LIMIT, start));
+        assert!(bucket.take(&LIMIT, start));
+
+        // Fail until a token is back, one every 20 seconds
+        assert!(!bucket.take(&LIMIT, start));
+        assert!(!bucket.take(&LIMIT, start + Duration::from_secs(19)));
+        assert!(bucket.take(&LIMIT, start + Duration::from_secs(20)));
+        assert!(!bucket.take(&LIMIT, start + Duration::from_secs(20)));
+
+        // The bucket never holds more than its capacity
+        let later = start + Duration::from_secs(3600);
+        assert!(bucket.take(&LIMIT, later));
+        assert!(bucket.take(&LIMIT, later));
+        assert!(bucket.take(&LIMIT, later));
+        assert!(!bucket.take(&LIMIT, later));
+    }
+
+    #[test]
+    fn subnet_mask() {
+        let ip = |ip: &str| ip.parse::<IpAddr>().unwrap();
+
+        assert_eq!(subnet(ip("192.168.1.77"), 32, 64), ip("192.168.1.77"));
+        assert_eq!(subnet(ip("192.168.1.77"), 24, 64), ip("192.168.1.0"));
+        assert_eq!(subnet(ip("192.168.1.77"), 0, 64), ip("0.0.0.0"));
+        assert_eq!(subnet(ip("2001:db8:1:2:3:4:5:6"), 32, 64), ip("2001:db8:1:2::"));
+        assert_eq!(subnet(ip("2001:db8:1:2:3:4:5:6"), 32, 128), ip("2001:db8:1:2:3:4:5:6"));
+        assert_eq!(subnet(ip("::ffff:192.168.1.77"), 24, 64), ip("192.168.1.0"));
+    }
+}
@@ -0,0 +1,238 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use tokio::time::Instant;
+
+use app_tools::security::crypto::{generate_random_bytes, hash_sha256, hmac_sha256, verify_hmac_sha256};
+use crate::authentication_tools::now;
+use crate::config::CONFIG;
+
+const TICKET_SIZE: usize = 32;
+const TAG_SIZE: usize = 32;
+
+lazy_static! {
+    /// Sessions and tickets are only kept in memory, a restart of the server ends every session,
+    /// so the key signing the tokens doesn't need to outlive the process
+    static ref SIGNING_KEY: Vec<u8> = {
+        let mut key = vec![0; 32];
+        generate_random_bytes(&mut key);
+        key
+    };
+    static ref SESSIONS: Mutex<HashMap<[u8; 16], Session>> = Mutex::new(HashMap::new());
+    /// Tickets are indexed by their hash, so the store itself doesn't hold usable tickets
+    static ref TICKETS: Mutex<HashMap<Vec<u8>, Ticket>> = Mutex::new(HashMap::new());
+}
+
+/// How the user proved its identity
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum AuthLevel {
+    /// Password only, or email token for a registration
+    OneFactor = 1,
+    /// Password and YubiKey, or YubiKey and email token for a reset
+    TwoFactor = 2,
+}
+
+impl AuthLevel {
+    fn from_u8(level: u8) -> Option<AuthLevel> {
+        match level {
+            1 => Some(AuthLevel::OneFactor),
+            2 => Some(AuthLevel::TwoFactor),
+            _ => None,
+        }
+    }
+}
+
+/// A logged in user, times are UNIX timestamps in seconds
+#[derive(Clone, PartialEq, Debug)]
+pub struct Session {
+    pub id: [u8; 16],
+    pub email: String,
+    pub issued: u64,
+    pub expires: u64,
+    pub auth_level: AuthLevel,
+}
+
+impl Session {
+    pub fn is_expired(&self) -> bool {
+        self.expires <= now()
+    }
+
+    /// The token is the session signed by the server, the client sends it with each action
+    pub fn token(&self) -> Vec<u8> {
+        let mut token = self.encode();
+        token.extend(hmac_sha256(&SIGNING_KEY, &token));
+        token
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(33 + self.email.len());
+        bytes.extend_from_slice(&self.id);
+        bytes.extend_from_slice(&self.issued.to_be_bytes());
+        bytes.extend_from_slice(&self.expires.to_be_bytes());
+        bytes.push(self.auth_level as u8);
+        bytes.extend_from_slice(self.email.as_bytes());
+        bytes
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Session> {
+        if bytes.len() < 33 {
+            return None;
+        }
+        Some(Session {
+            id: bytes[0..16].try_into().ok()?,
+            issued: u64::from_be_bytes(bytes[16..24].try_into().ok()?),
+            expires: u64::from_be_bytes(bytes[24..32].try_into().ok()?),
+            auth_level: AuthLevel::from_u8(bytes[32])?,
+            email: String::from_utf8(bytes[33..].to_vec()).ok()?,
+        })
+    }
+}
+
+struct Ticket {
+    session_id: [u8; 16],
+    expires: Instant,
+}
+
+/// Start a session for a user who has just logged in, it lasts `SESSION_LIFETIME` at most
+pub fn open(email: &str, auth_level: AuthLevel) -> Session {
+    let mut id = [0; 16];
+    generate_random_bytes(&mut id);
+    let now = now();
+    let session = Session {
+        id,
+        email: email.to_string(),
+        issued: now,
+        expires: now + CONFIG.session_lifetime.as_secs(),
+        auth_level,
+    };
+
+    let mut sessions = SESSIONS.lock().unwrap();
+    sessions.retain(|_, session| !session.is_expired());
+    sessions.insert(id, session.clone());
+    session
+}
+
+/// Check the signature and the expiry of the token, and that its session hasn't been revoked
+/// # Returns
+/// * `Option<Session>` - The session of the token, `None` if it can't be used
+pub fn verify(token: &[u8]) -> Option<Session> {
+    if token.len() < TAG_SIZE {
+        return None;
+    }
+    let (payload, tag) = token.split_at(token.len() - TAG_SIZE);
+    if !verify_hmac_sha256(&SIGNING_KEY, payload, tag) {
+        return None;
+    }
+
+    let session = Session::decode(payload)?;
+    if session.is_expired() || SESSIONS.lock().unwrap().get(&session.id) != Some(&session) {
+        return None;
+    }
+    Some(session)
+}
+
+/// End the session, its token and its tickets can't be used anymore
+pub fn revoke(session: &Session) {
+    SESSIONS.lock().unwrap().remove(&session.id);
+    TICKETS.lock().unwrap().retain(|_, ticket| ticket.session_id != session.id);
+}
+
+/// End every session of the user, e.g. when the password changes
+pub fn revoke_user(email: &str) {
+    let mut sessions = SESSIONS.lock().unwrap();
+    let revoked: Vec<[u8; 16]> = sessions.values()
+        .filter(|session| session.email == email)
+        .map(|session| session.id)
+        .collect();
+    sessions.retain(|_, session| session.email != email);
+    TICKETS.lock().unwrap().retain(|_, ticket| !revoked.contains(&ticket.session_id));
+}
+
+/// Give a new ticket for the session, it can be used once to resume it on a new connection
+/// until the resumption window has passed
+pub fn issue_ticket(session: &Session) -> Vec<u8> {
+    let mut ticket = vec![0; TICKET_SIZE];
+    generate_random_bytes(&mut ticket);
+
+    let mut tickets = TICKETS.lock().unwrap();
+    let now = Instant::now();
+    tickets.retain(|_, ticket| ticket.expires > now);
+    tickets.insert(hash_sha256(&ticket), Ticket {
+        session_id: session.id,
+        expires: now + CONFIG.resume_window,
+    });
+
+    ticket
+}
+
+/// Consume the ticket, the session keeps its expiry
+/// # Returns
+/// * `Option<Session>` - `None` if the ticket is unknown or expired, or if its session has ended
+pub fn resume(ticket: &[u8]) -> Option<Session> {
+    let ticket = TICKETS.lock().unwrap().remove(&hash_sha256(ticket))?;
+    if ticket.expires <= Instant::now() {
+        return None;
+    }
+    SESSIONS.lock().unwrap().get(&ticket.session_id)
+        .filter(|session| !session.is_expired())
+        .cloned()
+}
+
+/// The window starts again from the last activity of the session
+pub fn touch(ticket: &[u8]) {
+    if let Some(ticket) = TICKETS.lock().unwrap().get_mut(&hash_sha256(ticket)) {
+        ticket.expires = Instant::now() + CONFIG.resume_window;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn session_expiry() {
+        // Pass
+        let session = open("expiry@example.com", AuthLevel::OneFactor);
+        assert_eq!(verify(&session.token()), Some(session.clone()));
+
+        // Fail, the token is altered
+        let mut token = session.token();
+        token[32] = AuthLevel::TwoFactor as u8;
+        assert_eq!(verify(&token), None);
+        assert_eq!(verify(&token[..TAG_SIZE - 1]), None);
+
+        // Fail, the session has expired
+        let expired = Session {
+            id: [1; 16],
+            email: "expiry@example.com".to_string(),
+            issued: now() - 20,
+            expires: now() - 10,
+            auth_level: AuthLevel::OneFactor,
+        };
+        SESSIONS.lock().unwrap().insert(expired.id, expired.clone());
+        assert!(expired.is_expired());
+        assert_eq!(verify(&expired.token()), None);
+    }
+
+    #[test]
+    fn session_revocation() {
+        let first = open("revoked@example.com", AuthLevel::TwoFactor);
+        let second = open("revoked@example.com", AuthLevel::OneFactor);
+        let other = open("other@example.com", AuthLevel::OneFactor);
+        let first_ticket = issue_ticket(&first);
+        let second_ticket = issue_ticket(&second);
+        let other_ticket = issue_ticket(&other);
+
+        // A logout ends the session and its tickets only
+        revoke(&first);
+        assert_eq!(verify(&first.token()), None);
+        assert_eq!(resume(&first_ticket), None);
+        assert_eq!(verify(&second.token()), Some(second.clone()));
+
+        // A password change ends every session of the user
+        revoke_user("revoked@example.com");
+        assert_eq!(verify(&second.token()), None);
+        assert_eq!(resume(&second_ticket), None);
+        assert_eq!(verify(&other.token()), Some(other.clone()));
+        assert_eq!(resume(&other_ticket), Some(other));
+    }
+}
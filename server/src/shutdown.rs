@@ -0,0 +1,68 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tokio::signal;
+use tokio::sync::{watch, Notify};
+
+lazy_static! {
+    static ref TRIGGER: watch::Sender<bool> = watch::channel(false).0;
+    static ref ALL_DONE: Notify = Notify::new();
+}
+
+static RUNNING_TASKS: AtomicUsize = AtomicUsize::new(0);
+
+/// Held by every task that must be able to finish before the server stops
+pub struct TaskGuard;
+
+impl Drop for TaskGuard {
+    fn drop(&mut self) {
+        if RUNNING_TASKS.fetch_sub(1, Ordering::SeqCst) == 1 {
+            ALL_DONE.notify_waiters();
+        }
+    }
+}
+
+pub fn track() -> TaskGuard {
+    RUNNING_TASKS.fetch_add(1, Ordering::SeqCst);
+    TaskGuard
+}
+
+/// Wait until every tracked task is done
+pub async fn tasks_done() {
+    loop {
+        // Registered before checking, so a notification in between isn't missed
+        let notified = ALL_DONE.notified();
+        if RUNNING_TASKS.load(Ordering::SeqCst) == 0 {
+            return;
+        }
+        notified.await;
+    }
+}
+
+pub fn is_triggered() -> bool {
+    *TRIGGER.borrow()
+}
+
+/// Resolve once the shutdown has started
+pub async fn triggered() {
+    let mut receiver = TRIGGER.subscribe();
+    // Only fails if the sender is dropped, which never happens for a static
+    let _ = receiver.wait_for(|triggered| *triggered).await;
+}
+
+/// Wait for SIGINT (Ctrl-C) or SIGTERM and start the shutdown
+pub async fn wait_signal() {
+    #[cfg(unix)]
+    {
+        let mut terminate = signal::unix::signal(signal::unix::SignalKind::terminate())
+            .expect("Could not listen to SIGTERM");
+        tokio::select! {
+            _ = signal::ctrl_c() => {},
+            _ = terminate.recv() => {},
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = signal::ctrl_c().await;
+    }
+
+    TRIGGER.send_replace(true);
+}
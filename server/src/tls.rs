@@ -0,0 +1,66 @@
+use std::error::Error;
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::Arc;
+use rustls::{Certificate, PrivateKey, ServerConfig};
+use rustls_pemfile::Item;
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+use tokio_rustls::TlsAcceptor;
+use tokio_rustls::server::TlsStream;
+
+use crate::config::CONFIG;
+
+/// Build the TLS configuration from the certificate and key given in the config.
+/// # Returns
+/// * `Option<Arc<ServerConfig>>` - `None` only if plaintext has been explicitly enabled
+/// # Errors
+/// * `String` - The certificate or the key is missing or can't be loaded
+pub fn server_config() -> Result<Option<Arc<ServerConfig>>, Box<dyn Error + Send + Sync>> {
+    if CONFIG.insecure_plaintext {
+        println!("WARNING: TLS is disabled, everything is sent in clear (INSECURE_PLAINTEXT)");
+        return Ok(None);
+    }
+
+    let (cert_path, key_path) = match (&CONFIG.tls_cert, &CONFIG.tls_key) {
+        (Some(cert_path), Some(key_path)) => (cert_path, key_path),
+        _ => return Err("TLS_CERT and TLS_KEY must be set (or INSECURE_PLAINTEXT for local development)".into()),
+    };
+
+    let config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(load_certs(cert_path)?, load_private_key(key_path)?)?;
+
+    Ok(Some(Arc::new(config)))
+}
+
+/// TLS handshake with a new client, limited by the read timeout
+pub async fn accept(tls: &TlsAcceptor, stream: TcpStream) -> Result<TlsStream<TcpStream>, Box<dyn Error + Send + Sync>> {
+    match timeout(CONFIG.read_timeout, tls.accept(stream)).await {
+        Ok(stream) => Ok(stream?),
+        Err(_) => Err("TLS handshake timed out".into()),
+    }
+}
+
+fn load_certs(path: &str) -> Result<Vec<Certificate>, Box<dyn Error + Send + Sync>> {
+    let mut reader = BufReader::new(File::open(path)
+        .map_err(|e| format!("Could not read certificate {}: {}", path, e))?);
+    let certs = rustls_pemfile::certs(&mut reader)?;
+    if certs.is_empty() {
+        return Err(format!("No certificate found in {}", path).into());
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+fn load_private_key(path: &str) -> Result<PrivateKey, Box<dyn Error + Send + Sync>> {
+    let mut reader = BufReader::new(File::open(path)
+        .map_err(|e| format!("Could not read private key {}: {}", path, e))?);
+    while let Some(item) = rustls_pemfile::read_one(&mut reader)? {
+        match item {
+            Item::PKCS8Key(key) | Item::RSAKey(key) | Item::ECKey(key) => return Ok(PrivateKey(key)),
+            _ => {}
+        }
+    }
+    Err(format!("No private key found in {}", path).into())
+}
@@ -0,0 +1,141 @@
+use serde::{Serialize, Deserialize};
+use app_tools::communication::data::VerificationPurpose;
+use app_tools::security::crypto::{hash_sha256, SecretBytes};
+use crate::authentication::User;
+use crate::authentication_tools::now;
+use crate::config::CONFIG;
+
+/// Email token waiting to be confirmed, it is saved in the database so the user can finish
+/// the verification on a new connection. Times are UNIX timestamps in seconds.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PendingVerification {
+    pub purpose: VerificationPurpose,
+    pub email: String,
+    /// The database only holds the hash of the token, it can't be used to finish the verification
+    token_hash: SecretBytes,
+    pub expires: u64,
+    pub attempts: u32,
+    /// Account created once its email is confirmed, `None` for a password reset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user: Option<User>,
+}
+
+impl PendingVerification {
+    /// The token can be used until `VERIFICATION_LIFETIME` has passed
+    pub fn new(purpose: VerificationPurpose, email: &str, token: &str, user: Option<User>) -> PendingVerification {
+        PendingVerification {
+            purpose,
+            email: email.to_string(),
+            token_hash: hash_token(token),
+            expires: now() + CONFIG.verification_lifetime.as_secs(),
+            attempts: 0,
+            user,
+        }
+    }
+
+    pub fn is_for(&self, purpose: VerificationPurpose, email: &str) -> bool {
+        self.purpose == purpose && self.email == email
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expires <= now()
+    }
+
+    pub fn matches(&self, token: &str) -> bool {
+        self.token_hash.matches(&hash_token(token))
+    }
+
+    /// Count a wrong token
+    /// # Returns
+    /// * `bool` - `true` once `VERIFICATION_MAX_ATTEMPTS` is reached, the token can't be used anymore
+    pub fn record_attempt(&mut self) -> bool {
+        self.attempts = self.attempts.saturating_add(1);
+        self.attempts >= CONFIG.verification_max_attempts
+    }
+}
+
+fn hash_token(token: &str) -> SecretBytes {
+    SecretBytes::new(hash_sha256(token.as_bytes()))
+}
+
+/// Check the token of the pending verification of the email for `purpose`, it is removed once confirmed,
+/// expired or after too many wrong tokens
+/// # Returns
+/// * `(Option<PendingVerification>, bool)` - The confirmed verification, and whether `pending` has changed
+pub fn consume(pending: &mut Vec<PendingVerification>,
+               purpose: VerificationPurpose,
+               email: &str,
+               token: &str) -> (Option<PendingVerification>, bool) {
+    let count = pending.len();
+    pending.retain(|pending| !pending.is_expired());
+    let expired = pending.len() != count;
+
+    let index = match pending.iter().position(|pending| pending.is_for(purpose, email)) {
+        Some(index) => index,
+        None => return (None, expired),
+    };
+    if pending[index].matches(token) {
+        return (Some(pending.remove(index)), true);
+    }
+    if pending[index].record_attempt() {
+        pending.remove(index);
+    }
+    (None, true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use VerificationPurpose::{PasswordReset, Registration};
+
+    const EMAIL: &str = "email@example.com";
+
+    #[test]
+    fn consume_token() {
+        let mut pending = vec![
+            PendingVerification::new(Registration, EMAIL, "token", None),
+            PendingVerification::new(PasswordReset, EMAIL, "other", None),
+        ];
+
+        // Pass, only the verification of the purpose is consumed
+        let (confirmed, changed) = consume(&mut pending, Registration, EMAIL, "token");
+        assert!(confirmed.is_some_and(|confirmed| confirmed.purpose == Registration));
+        assert!(changed);
+        assert_eq!(pending.len(), 1);
+
+        // Fail, nothing to confirm and nothing to save
+        assert!(!consume(&mut pending, Registration, EMAIL, "token").1);
+        assert!(!consume(&mut pending, PasswordReset, "other@example.com", "other").1);
+        assert_eq!(pending.len(), 1);
+    }
+
+    #[test]
+    fn consume_wrong_tokens() {
+        let mut pending = vec![PendingVerification::new(PasswordReset, EMAIL, "token", None)];
+
+        // Each wrong token is counted, the last one invalidates the verification
+        for attempt in 1..CONFIG.verification_max_attempts {
+            let (confirmed, changed) = consume(&mut pending, PasswordReset, EMAIL, "wrong");
+            assert!(confirmed.is_none());
+            assert!(changed);
+            assert_eq!(pending[0].attempts, attempt);
+        }
+        assert!(consume(&mut pending, PasswordReset, EMAIL, "wrong").0.is_none());
+        assert!(pending.is_empty());
+
+        // Fail, even with the right token
+        assert!(consume(&mut pending, PasswordReset, EMAIL, "token").0.is_none());
+    }
+
+    #[test]
+    fn consume_expired() {
+        let mut expired = PendingVerification::new(PasswordReset, EMAIL, "token", None);
+        expired.expires = now() - 1;
+        let mut pending = vec![expired];
+
+        let (confirmed, changed) = consume(&mut pending, PasswordReset, EMAIL, "token");
+        assert!(confirmed.is_none());
+        assert!(changed);
+        assert!(pending.is_empty());
+    }
+}
@@ -0,0 +1,49 @@
+#!/bin/sh
+# Certificates of the attestation tests, shaped like the Yubico chain: root, attestation key of a YubiKey,
+# and slot certificate with the extensions of https://developers.yubico.com/PIV/Introduction/PIV_attestation.html
+# They are valid for 100 years, run this script in its directory to make new ones.
+set -e
+work=$(mktemp -d)
+trap 'rm -rf "$work"' EXIT
+
+cat > "$work/ext.cnf" <<'CNF'
+[ca]
+basicConstraints = critical,CA:true
+keyUsage = keyCertSign
+[slot]
+1.3.6.1.4.1.41482.3.3 = DER:05:04:03
+1.3.6.1.4.1.41482.3.7 = ASN1:INTEGER:12345678
+1.3.6.1.4.1.41482.3.8 = DER:03:01
+[none]
+subjectKeyIdentifier = hash
+CNF
+
+# ca <name> <subject>, self-signed
+ca() {
+    openssl req -x509 -newkey rsa:2048 -nodes -keyout "$work/$1.key" -subj "/CN=$2" -days 36500 \
+        -addext "basicConstraints=critical,CA:true" -addext "keyUsage=keyCertSign" -out "$work/$1.pem"
+    openssl x509 -in "$work/$1.pem" -outform DER -out "$1.der"
+}
+
+# sign <name> <key> <subject> <issuer> <extensions>
+sign() {
+    openssl req -new -key "$work/$2.key" -subj "/CN=$3" -out "$work/$1.csr"
+    openssl x509 -req -in "$work/$1.csr" -CA "$work/$4.pem" -CAkey "$work/$4.key" -CAcreateserial \
+        -days 36500 -extfile "$work/ext.cnf" -extensions "$5" -out "$work/$1.pem"
+    openssl x509 -in "$work/$1.pem" -outform DER -out "$1.der"
+}
+
+ca root "Yubico PIV Root CA Serial 263751"
+# Same subject as the attestation certificate of the YubiKey, but not signed by the root
+ca rogue_intermediate "Yubico PIV Attestation"
+
+openssl genrsa -out "$work/intermediate.key" 2048
+openssl ecparam -name prime256v1 -genkey -noout -out "$work/slot.key"
+
+sign intermediate intermediate "Yubico PIV Attestation" root ca
+sign slot slot "YubiKey PIV Attestation 9a" intermediate slot
+sign no_extensions slot "YubiKey PIV Attestation 9a" intermediate none
+sign rogue_slot slot "YubiKey PIV Attestation 9a" rogue_intermediate slot
+
+# Public point of the slot key, as sent by the client
+openssl ec -in "$work/slot.key" -pubout -outform DER | tail -c 65 > slot.pub